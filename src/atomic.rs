@@ -0,0 +1,24 @@
+// Files are written to a temporary file next to the target and renamed into place once complete,
+// so an interrupted run leaves either the previous file or a complete new one, never a truncated one
+use std::{fs, io, path::Path};
+use crate::error::HerzError;
+
+// Calls write with the temporary path, then moves the result over path. On error the temporary file is removed.
+// The writer can use its own error type, as long as it can hold the library's errors
+pub fn write_atomic<F, E>(path: &str, write: F) -> Result<(), E>
+where
+    F: FnOnce(&Path) -> Result<(), E>,
+    E: From<HerzError>,
+{
+    let target = Path::new(path);
+    let name = target.file_name()
+        .ok_or_else(|| HerzError::io("write", path)(io::Error::new(io::ErrorKind::InvalidInput, "not a file path")))?;
+    let temp = target.with_file_name(format!(".{}.{}.tmp", name.to_string_lossy(), std::process::id()));
+    let result = write(&temp).and_then(|()| {
+        fs::rename(&temp, target).map_err(|e| HerzError::io("move into place", &temp.to_string_lossy())(e).into())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
@@ -0,0 +1,74 @@
+// Limits on how long a conversion may take and how large its output may grow, so a hosted
+// deployment can abort pathological inputs instead of running them to completion
+use std::{io::{self, Write}, time::{Duration, Instant}};
+use crate::error::{HerzError, Result};
+
+pub struct Budget {
+    start: Instant,
+    max_time: Option<Duration>,
+    max_output_bytes: Option<u64>,
+}
+
+impl Budget {
+    // The time limit counts from now
+    pub fn new(max_time: Option<Duration>, max_output_bytes: Option<u64>) -> Budget {
+        Budget { start: Instant::now(), max_time, max_output_bytes }
+    }
+
+    pub fn unlimited() -> Budget {
+        Budget::new(None, None)
+    }
+
+    // Fails once the time limit has passed, naming the phase that was about to start or running
+    pub fn check_time(&self, phase: &str) -> Result<()> {
+        match self.max_time {
+            Some(limit) if self.start.elapsed() > limit => Err(HerzError::TimeLimit { limit, phase: phase.to_string() }),
+            _ => Ok(()),
+        }
+    }
+
+    // Fails when `bytes` of output already go over the size limit
+    pub fn check_output(&self, bytes: u64) -> Result<()> {
+        match self.max_output_bytes {
+            Some(limit) if bytes > limit => Err(HerzError::OutputLimit { limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Counts the bytes written through it and checks the budget on every write, so a slow or huge
+// output is cut off while it streams. Budget errors are carried inside the io::Error, see `into_herz`
+pub struct BudgetWriter<'a, W: Write> {
+    inner: W,
+    budget: &'a Budget,
+    written: u64,
+}
+
+impl<'a, W: Write> BudgetWriter<'a, W> {
+    pub fn new(inner: W, budget: &'a Budget) -> BudgetWriter<'a, W> {
+        BudgetWriter { inner, budget, written: 0 }
+    }
+}
+
+impl<W: Write> Write for BudgetWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.budget.check_time("writing the output").map_err(io::Error::other)?;
+        self.budget.check_output(self.written + buf.len() as u64).map_err(io::Error::other)?;
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Unwraps a budget error raised by a BudgetWriter, any other I/O error goes through `wrap`
+pub(crate) fn into_herz(error: io::Error, wrap: impl FnOnce(io::Error) -> HerzError) -> HerzError {
+    if error.get_ref().is_some_and(|inner| inner.is::<HerzError>()) {
+        let inner = error.into_inner().expect("checked above");
+        return *inner.downcast::<HerzError>().expect("checked above");
+    }
+    wrap(error)
+}
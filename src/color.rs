@@ -0,0 +1,91 @@
+// Color spaces the palette matching can measure distances in.
+// Matching converts both the palette and the source colors, the written colors always stay the palette's RGB
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorSpace {
+    // Plain sRGB distances, fastest and the historical behavior
+    #[default]
+    Rgb,
+    // CIELAB (D65), closer to perceived differences
+    Lab,
+    // OKLab, more perceptually uniform than CIELAB for saturated colors
+    Oklab,
+}
+
+impl ColorSpace {
+    // Converts an 8-bit sRGB point (channels in 0..=255) into this color space
+    pub fn convert(self, rgb: [f64; 3]) -> [f64; 3] {
+        match self {
+            ColorSpace::Rgb => rgb,
+            ColorSpace::Lab => linear_to_lab(srgb_to_linear(rgb)),
+            ColorSpace::Oklab => linear_to_oklab(srgb_to_linear(rgb)),
+        }
+    }
+}
+
+// Removes the sRGB transfer curve, giving linear light in 0..=1. Shared by Lab and OKLab
+pub fn srgb_to_linear(rgb: [f64; 3]) -> [f64; 3] {
+    rgb.map(|c| {
+        let c = c / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    })
+}
+
+// Adds the sRGB transfer curve back to a linear light channel in 0..=1, giving an 8-bit channel
+pub fn linear_to_srgb(c: f64) -> u8 {
+    let c = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+// Mixes two sRGB colors in linear light, `t` = 0 giving `a` and 1 giving `b`. Mixing the encoded
+// values instead would make the in-between colors darker than either side looks
+pub fn blend_linear(a: [u8; 3], b: [u8; 3], t: f64) -> [u8; 3] {
+    let (a, b) = (srgb_to_linear(a.map(f64::from)), srgb_to_linear(b.map(f64::from)));
+    [0, 1, 2].map(|i| linear_to_srgb(a[i] + (b[i] - a[i]) * t))
+}
+
+// Shifts the hue of an sRGB color by `hue` degrees (wrapping around) and adds `saturation` and `brightness`
+// (-1.0 to 1.0) to its HSV saturation and value, clamping both to 0..=1
+pub fn adjust_hsv(rgb: [u8; 3], hue: f64, saturation: f64, brightness: f64) -> [u8; 3] {
+    let [r, g, b] = rgb.map(|c| c as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    let h = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / chroma + 2.0)
+    } else {
+        60.0 * ((r - g) / chroma + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { chroma / max };
+    let (h, s, v) = ((h + hue).rem_euclid(360.0), (s + saturation).clamp(0.0, 1.0), (max + brightness).clamp(0.0, 1.0));
+    // Back from HSV: the channel values along the hue circle's six sectors
+    let channel = |n: f64| {
+        let k = (n + h / 60.0) % 6.0;
+        v - v * s * k.min(4.0 - k).clamp(0.0, 1.0)
+    };
+    [channel(5.0), channel(3.0), channel(1.0)].map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+// Linear sRGB to CIELAB under the D65 white point
+fn linear_to_lab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+    let f = |t: f64| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// Linear sRGB to OKLab (Björn Ottosson's matrices)
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
@@ -0,0 +1,33 @@
+// Elevation contour lines over a map preview, like the lines of a topographic map. A visualization only,
+// the map itself has no contours
+use image::{Rgb, RgbImage};
+
+// How much of a tile's color a contour keeps, dark enough to read over light and dark tiles alike
+const LINE_SHADE: f32 = 0.35;
+
+// Darkens every tile that lies on an elevation contour, `heights` holding one 0-255 value per tile (row-major).
+// Contours are the boundaries between the bands 0..interval, interval..2*interval and so on: a tile is on a
+// line when one of its four neighbors is in a lower band, so the line is one tile wide and follows the
+// higher side of the boundary. Returns the number of tiles darkened
+pub fn draw(img: &mut RgbImage, heights: &[u8], interval: u8) -> usize {
+    let (w, h) = img.dimensions();
+    let band = |x: u32, y: u32| heights[(y * w + x) as usize] / interval.max(1);
+    let mut drawn = 0;
+    for y in 0..h {
+        for x in 0..w {
+            let own = band(x, y);
+            let neighbors = [
+                (x > 0).then(|| band(x - 1, y)),
+                (x + 1 < w).then(|| band(x + 1, y)),
+                (y > 0).then(|| band(x, y - 1)),
+                (y + 1 < h).then(|| band(x, y + 1)),
+            ];
+            if neighbors.into_iter().flatten().any(|other| other < own) {
+                let Rgb(color) = *img.get_pixel(x, y);
+                img.put_pixel(x, y, Rgb(color.map(|c| (c as f32 * LINE_SHADE).round() as u8)));
+                drawn += 1;
+            }
+        }
+    }
+    drawn
+}
@@ -0,0 +1,34 @@
+// Median filtering of source images, to clean up compression noise (ex: JPEG 8x8 blocks) before matching
+use image::RgbImage;
+use rayon::prelude::*;
+
+// Replaces every channel of every pixel with its median over the (2 * radius + 1)² square around it,
+// edges repeating their outermost pixels. The median only ever picks a value already in the window,
+// so it needs no clamping and gives the same result in linear light as on the sRGB values
+pub fn median(img: &RgbImage, radius: u32) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let r = radius as i64;
+    let src = img.as_raw();
+    let mut out = img.clone();
+    out.as_mut().par_chunks_mut(width as usize * 3).enumerate().for_each(|(y, row)| {
+        let mut window: [Vec<u8>; 3] = Default::default();
+        for x in 0..width as usize {
+            window.iter_mut().for_each(Vec::clear);
+            for dy in -r..=r {
+                let sy = (y as i64 + dy).clamp(0, height as i64 - 1) as usize;
+                for dx in -r..=r {
+                    let sx = (x as i64 + dx).clamp(0, width as i64 - 1) as usize;
+                    let i = (sy * width as usize + sx) * 3;
+                    for (channel, values) in window.iter_mut().enumerate() {
+                        values.push(src[i + channel]);
+                    }
+                }
+            }
+            for (channel, values) in window.iter_mut().enumerate() {
+                let middle = values.len() / 2;
+                row[x * 3 + channel] = *values.select_nth_unstable(middle).1;
+            }
+        }
+    });
+    out
+}
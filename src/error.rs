@@ -0,0 +1,57 @@
+// Errors returned by the library, one variant per kind of failure so frontends can match on them
+use std::{io, time::Duration};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HerzError {
+    // Reading or writing a file failed
+    #[error("Failed to {action} {path}")]
+    Io {
+        action: &'static str,
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    // A file exists but its contents can't be decoded (ex: not zlib data, not JSON)
+    #[error("{path} is not a valid {kind} file")]
+    Decode {
+        path: String,
+        kind: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    // The palette is empty, has duplicate ids or is too large
+    #[error("Invalid palette: {0}")]
+    Palette(String),
+    // No palette color is left to match against (ex: every id is locked)
+    #[error("{0}")]
+    Matching(String),
+    // tileArray/tileAmounts data that doesn't describe a full grid
+    #[error("Invalid tile data: {0}")]
+    Template(String),
+    // A lookup table that doesn't fit the palette or options it is used with
+    #[error("Invalid LUT: {0}")]
+    Lut(String),
+    // The conversion went past its time budget, `phase` is what it was doing at the time
+    #[error("Exceeded the time limit of {limit:?} while {phase}")]
+    TimeLimit { limit: Duration, phase: String },
+    // The output grew past its size budget and was abandoned
+    #[error("Output exceeds the limit of {limit} bytes")]
+    OutputLimit { limit: u64 },
+}
+
+pub type Result<T> = std::result::Result<T, HerzError>;
+
+impl HerzError {
+    // Wraps an I/O error with what was being done to which file, for use with map_err
+    pub(crate) fn io(action: &'static str, path: &str) -> impl FnOnce(io::Error) -> HerzError {
+        move |source| HerzError::Io { action, path: path.to_string(), source }
+    }
+
+    // Wraps a decoding error with the file and what it was expected to be, for use with map_err
+    pub(crate) fn decode<E>(kind: &'static str, path: &str) -> impl FnOnce(E) -> HerzError
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        move |source| HerzError::Decode { path: path.to_string(), kind, source: Box::new(source) }
+    }
+}
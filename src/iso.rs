@@ -0,0 +1,41 @@
+// Approximate 2.5D preview of a map: tiles are raised by their height and lit from the top-left,
+// so elevation can be judged at a glance. It is a visualization only, not how the game renders
+use image::{Rgb, RgbImage};
+
+// Darkening of the cliff faces below a raised tile
+const SIDE_SHADE: f32 = 0.6;
+
+// Renders the tile colors raised by `heights` (one 0-255 value per tile, row-major), seen from the
+// front at an angle. The image keeps the map's width and grows by the highest lift
+pub fn render(img: &RgbImage, heights: &[u8]) -> RgbImage {
+    let (w, h) = img.dimensions();
+    // Tiles rise up to an eighth of the map's height, rows are squashed to half height for the angle
+    let max_lift = (h / 8).max(1);
+    let lift = |x: u32, y: u32| heights[(y * w + x) as usize] as u32 * max_lift / 255;
+    let height_at = |x: i64, y: i64| heights[(y.clamp(0, h as i64 - 1) as u32 * w + x.clamp(0, w as i64 - 1) as u32) as usize] as f32;
+    let rows = h.div_ceil(2);
+    let mut out = RgbImage::from_pixel(w, rows + max_lift, Rgb([16, 16, 24]));
+
+    // Back rows first so nearer tiles cover the ones behind them
+    for y in 0..h {
+        for x in 0..w {
+            let base = y / 2 + max_lift;
+            let top = base - lift(x, y);
+            // Slopes facing the light from the top-left are brighter, the others darker
+            let (xi, yi) = (x as i64, y as i64);
+            let slope = (height_at(xi - 1, yi) - height_at(xi + 1, yi)) + (height_at(xi, yi - 1) - height_at(xi, yi + 1));
+            let light = (1.0 - slope / 255.0).clamp(0.4, 1.4);
+            let color = *img.get_pixel(x, y);
+            out.put_pixel(x, top, shade(color, light));
+            for sy in top + 1..=base.min(out.height() - 1) {
+                out.put_pixel(x, sy, shade(color, SIDE_SHADE));
+            }
+        }
+    }
+    out
+}
+
+fn shade(Rgb([r, g, b]): Rgb<u8>, factor: f32) -> Rgb<u8> {
+    let channel = |c: u8| (c as f32 * factor).round().clamp(0.0, 255.0) as u8;
+    Rgb([channel(r), channel(g), channel(b)])
+}
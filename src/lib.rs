@@ -0,0 +1,32 @@
+// HerzMapper library: the palette matching behind the command-line tool,
+// usable on its own as an image color quantizer
+pub mod atomic;
+pub mod budget;
+pub mod color;
+pub mod contour;
+pub mod denoise;
+pub mod error;
+pub mod iso;
+pub mod lut;
+pub mod noise;
+pub mod palette;
+pub mod quality;
+pub mod quantize;
+pub mod rle;
+pub mod sheet;
+pub mod simplify;
+pub mod text;
+pub mod tiers;
+pub mod tiled;
+pub mod wbox;
+
+pub use atomic::write_atomic;
+pub use budget::Budget;
+pub use color::ColorSpace;
+pub use error::HerzError;
+pub use lut::{Lut, LutWarmup};
+pub use palette::{hex_to_rgb, parse_hex, MergedEntry, Palette, DEFAULT_CSV_COLUMNS};
+pub use quantize::{apply_grid, apply_matches, match_colors, match_distance, match_pixels, quantize, unique_colors, QuantizeOptions};
+pub use rle::{rle_decode, rle_encode, rle_update};
+pub use tiers::TieredPalette;
+pub use wbox::{compress_to_wbox, compress_to_wbox_within, compress_counted, compress_wbox, compress_wbox_in, compress_wbox_to_vec, read_wbox, CompressionLevel, Container, Layer, WboxDocument};
@@ -0,0 +1,181 @@
+// Precomputed RGB lookup tables, so conversions with a fixed palette skip the kd-tree queries
+use std::{fs::File, io::{self, BufReader, BufWriter, Read, Write}, sync::{Arc, Mutex, OnceLock}, thread::{self, JoinHandle}};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+use crate::{atomic::write_atomic, error::{HerzError, Result}, palette::Palette, quantize::{match_colors, QuantizeOptions}};
+
+// Nearest palette index of every sample of the RGB cube, taken every `step` values per channel
+pub struct Lut {
+    step: u8,
+    // Samples per channel, the last one always being 255
+    samples: usize,
+    indices: Vec<u16>,
+}
+
+// What the table was built from, checked when loading so a stale table is never used
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Header {
+    step: u8,
+    palette: Vec<(String, [u8; 3])>,
+    color_space: String,
+    locked_ids: Vec<String>,
+    // Tables written before --clamp-gamut existed were built without it
+    #[serde(default)]
+    clamp_gamut: bool,
+}
+
+impl Header {
+    fn new(step: u8, palette: &Palette, opts: &QuantizeOptions) -> Header {
+        // Exact-only palette ids are left out of the search like locked ones
+        let exact_only = (0..palette.len()).filter(|&i| palette.exact_only[i]).map(|i| palette.ids[i].clone());
+        let mut locked_ids: Vec<String> = opts.locked_ids.iter().cloned().chain(exact_only).collect();
+        locked_ids.sort();
+        locked_ids.dedup();
+        Header {
+            step,
+            palette: (0..palette.len()).map(|i| {
+                let (r, g, b) = palette.color(i);
+                (palette.ids[i].clone(), [r, g, b])
+            }).collect(),
+            color_space: format!("{:?}", opts.color_space),
+            locked_ids,
+            clamp_gamut: opts.clamp_gamut,
+        }
+    }
+}
+
+impl Lut {
+    // Matches every sample of the RGB cube with the same options a conversion would use
+    pub fn build(palette: &Palette, opts: &QuantizeOptions, step: u8) -> Result<Lut> {
+        check_buildable(palette, step)?;
+        let samples = 255usize.div_ceil(step as usize) + 1;
+        let value = |i: usize| (i * step as usize).min(255) as u8;
+        // One pixel per sample, blue varying fastest, so the sample index is the pixel index
+        let cube = RgbImage::from_fn(samples as u32, (samples * samples) as u32, |x, y| {
+            let (r, g) = (y as usize / samples, y as usize % samples);
+            image::Rgb([value(r), value(g), value(x as usize)])
+        });
+        let opts = QuantizeOptions { lut: None, ..opts.clone() };
+        let matches = match_colors(&cube, palette, &opts)?;
+        let indices = cube.pixels().map(|p| matches[&(p[0], p[1], p[2])] as u16).collect();
+        Ok(Lut { step, samples, indices })
+    }
+
+    // The palette index of the sample nearest to a color
+    pub fn lookup(&self, (r, g, b): (u8, u8, u8)) -> usize {
+        let sample = |c: u8| ((c as usize + self.step as usize / 2) / self.step as usize).min(self.samples - 1);
+        self.indices[(sample(r) * self.samples + sample(g)) * self.samples + sample(b)] as usize
+    }
+
+    // Writes the table as a zlib-compressed file: a JSON header line describing what it was built from,
+    // then one little-endian u16 palette index per sample
+    pub fn save(&self, path: &str, palette: &Palette, opts: &QuantizeOptions) -> Result<()> {
+        write_atomic(path, |temp| {
+            let write = || -> io::Result<()> {
+                let mut writer = BufWriter::new(ZlibEncoder::new(File::create(temp)?, Compression::default()));
+                serde_json::to_writer(&mut writer, &Header::new(self.step, palette, opts))?;
+                writer.write_all(b"\n")?;
+                for index in &self.indices {
+                    writer.write_all(&index.to_le_bytes())?;
+                }
+                writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+                Ok(())
+            };
+            write().map_err(HerzError::io("write LUT", path))
+        })
+    }
+
+    // Reads a table, failing unless it was built for this palette, color space, set of locked ids and gamut clamping
+    pub fn load(path: &str, palette: &Palette, opts: &QuantizeOptions) -> Result<Lut> {
+        let file = File::open(path).map_err(HerzError::io("open LUT", path))?;
+        let mut data = Vec::new();
+        ZlibDecoder::new(BufReader::new(file))
+            .read_to_end(&mut data)
+            .map_err(HerzError::decode("LUT", path))?;
+        let newline = data.iter().position(|&b| b == b'\n')
+            .ok_or_else(|| HerzError::Lut(format!("{} has no LUT header", path)))?;
+        let header: Header = serde_json::from_slice(&data[..newline]).map_err(HerzError::decode("LUT", path))?;
+        if header != Header::new(header.step, palette, opts) {
+            return Err(HerzError::Lut(format!(
+                "{} was built for a different palette, color space, set of locked ids or --clamp-gamut setting, rebuild it with --build-lut", path
+            )));
+        }
+        let samples = 255usize.div_ceil(header.step as usize) + 1;
+        let body = &data[newline + 1..];
+        if body.len() != samples * samples * samples * 2 {
+            return Err(HerzError::Lut(format!("{} holds {} bytes of samples instead of {}", path, body.len(), samples * samples * samples * 2)));
+        }
+        let indices: Vec<u16> = body.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+        if let Some(index) = indices.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(HerzError::Lut(format!("{} refers to palette index {} but the palette has {} colors", path, index, palette.len())));
+        }
+        Ok(Lut { step: header.step, samples, indices })
+    }
+
+    pub fn step(&self) -> u8 {
+        self.step
+    }
+
+    // Size of the table in memory
+    pub fn size_bytes(&self) -> usize {
+        self.indices.len() * 2
+    }
+}
+
+// Fails when no table can be built for this palette and step
+fn check_buildable(palette: &Palette, step: u8) -> Result<()> {
+    if step == 0 {
+        return Err(HerzError::Lut("LUT step must be at least 1".to_string()));
+    }
+    if palette.len() > u16::MAX as usize {
+        return Err(HerzError::Lut(format!("LUTs support palettes of up to {} colors", u16::MAX)));
+    }
+    Ok(())
+}
+
+// A LUT built on a background thread right after the palette is loaded, for frontends converting
+// images interactively: conversions search the kd-tree until the table is ready, then only look up
+pub struct LutWarmup {
+    opts: QuantizeOptions,
+    lut: Arc<OnceLock<Arc<Lut>>>,
+    builder: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl LutWarmup {
+    // Starts building the table for the palette and matching options. It shares the rayon pool with
+    // the conversions running meanwhile. Fails right away, like `Lut::build`, on a step of 0 or a
+    // palette too large for a LUT
+    pub fn start(palette: Arc<Palette>, opts: &QuantizeOptions, step: u8) -> Result<LutWarmup> {
+        check_buildable(&palette, step)?;
+        let opts = QuantizeOptions { lut: None, ..opts.clone() };
+        let lut = Arc::new(OnceLock::new());
+        let builder = {
+            let (lut, opts) = (Arc::clone(&lut), opts.clone());
+            // A palette that can't be matched at all never gets a table, its conversions fail on their own
+            thread::spawn(move || {
+                if let Ok(built) = Lut::build(&palette, &opts, step) {
+                    let _ = lut.set(Arc::new(built));
+                }
+            })
+        };
+        Ok(LutWarmup { opts, lut, builder: Mutex::new(Some(builder)) })
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.lut.get().is_some()
+    }
+
+    // The matching options to convert with now: the table once it is built, the live kd-tree search before
+    pub fn options(&self) -> QuantizeOptions {
+        QuantizeOptions { lut: self.lut.get().cloned(), ..self.opts.clone() }
+    }
+
+    // Blocks until the table is built, None when it couldn't be
+    pub fn wait(&self) -> Option<Arc<Lut>> {
+        if let Some(builder) = self.builder.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = builder.join();
+        }
+        self.lut.get().cloned()
+    }
+}
@@ -1,272 +1,3076 @@
-use std::{
-    collections::{HashMap, HashSet},
-    fs,
-    fs::File,
-    io::Write,
-    io::stdin,
-    time::Instant,
-};
-use clap::Parser;
-use image::{open, imageops::resize, ImageBuffer, Rgb, ImageReader};
-use kiddo::{KdTree, SquaredEuclidean};
-use rayon::prelude::*;
-use serde_json::{json, Value};
-use flate2::{write::ZlibEncoder, Compression};
-use anyhow::{Context, Result};
-
-// Command-line arguments for the program
-#[derive(Parser)]
-#[command(name = "Image Processor")]
-#[command(about = "Processes an image using a color palette and compresses output", long_about = None)]
-struct Args {
-    // Path to the input image file (ex: "images/example.png")
-    #[arg(value_name = "IMAGE_FILE", help = "Specify the input image file (ex: images/example.png)")]
-    input: Option<String>, // Now optional to allow drag-and-drop
-
-    // Path to the palette file containing color mappings (ex: "palettes/all.txt")
-    #[arg(short, long, value_name = "PALETTE_FILE", default_value = "palettes/no-special.txt", help = "Specify the color palette file (ex: palette/all.txt)")]
-    palette: String,
-
-    // Path to the JSON map data file. Defaults to "map_data.json" if not provided
-    #[arg(short, long = "map-data", default_value = "map_data.json", value_name = "MAP_JSON", help = "Specify the JSON map data file")]
-    map_data: String,
-
-    // Output file where the processed data will be saved. Defaults to "map.wbox"
-    #[arg(short, long, default_value = "map.wbox", value_name = "OUTPUT_FILE", help = "Specify the output file name")]
-    output: String,
-
-    // Optional path to a world laws file (ex: worldlaws/default.txt)
-    #[arg(short, long, value_name = "WORLD_LAWS_FILE",  default_value = "worldlaws/default.txt", help = "Path to a world laws .txt file")]
-    world_laws: String,
-
-    // Optional freeze map image: white pixels in this image will be marked as frozen
-    #[arg(short, long, value_name = "FREEZE_MAP_IMAGE", help = "Specify an optional freeze map image file (ex: images/frozen.png)")]
-    freeze_map: Option<String>,
-
-    // Disables pause before exiting
-    #[arg(short, long, value_name = "NO_PAUSE", default_value_t = true, action = clap::ArgAction::SetFalse, help = "Specify this if you don't want the program to pause before exit")]
-    no_pause: bool,
-}
-
-// Resizes an image to the nearest multiple of 64, ensuring it's at least 128x128
-fn resize_to_nearest_64(img: ImageBuffer<Rgb<u8>, Vec<u8>>) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let (width, height) = img.dimensions();
-    let new_width = ((width + 63) / 64).max(2) * 64;
-    let new_height = ((height + 63) / 64).max(2) * 64;
-    resize(&img, new_width, new_height, image::imageops::FilterType::Nearest)
-}
-
-// Converts a hex color string (e.g., "#RRGGBB") to an RGB tuple
-fn hex_to_rgb(hex: &str) -> Option<(u8, u8, u8)> {
-    u32::from_str_radix(hex.trim_start_matches('#'), 16).ok().map(|c| {
-        (
-            ((c >> 16) & 0xff) as u8,
-            ((c >> 8) & 0xff) as u8,
-            (c & 0xff) as u8,
-        )
-    })
-}
-
-// Compresses the given JSON data string to a .wbox file using zlib compression
-fn compress_to_wbox(json_data: &str, output_path: &str) -> std::io::Result<()> {
-    let output_file = File::create(output_path)?;
-    let mut encoder = ZlibEncoder::new(output_file, Compression::fast());
-    encoder.write_all(json_data.as_bytes())?;
-    encoder.finish()?;
-    Ok(())
-}
-
-fn main() {
-    let args = Args::parse();
-    if let Err(e) = run() {
-        eprintln!("Error: {:?}", e);
-    }
-
-    if args.no_pause {
-        println!("Press Enter to exit...");
-        let mut input = String::new();
-        stdin().read_line(&mut input).unwrap();
-    }
-}
-
-
-fn run() -> Result<()> {
-
-    let args = Args::parse();
-    let start = Instant::now();
-
-    // Load the palette file ("palette.txt") where each line is "id #RRGGBB"
-    let palette_content = fs::read_to_string(&args.palette)
-        .with_context(|| format!("Failed to read palette file: {}", args.palette))?;
-    let (mut palette_ids, mut palette_points) = (Vec::new(), Vec::new());
-    for line in palette_content.lines() {
-        if let Some((id, hex)) = line.split_once(' ') {
-            if let Some((r, g, b)) = hex_to_rgb(hex) {
-                palette_ids.push(id.to_string());
-                palette_points.push([r as f64, g as f64, b as f64]);
-            }
-        }
-    }
-
-    // Build a kd-tree from the palette (3-dimensional points, storing u64 indices)
-    let mut kdtree: KdTree<f64, 3> = KdTree::new();
-    for (i, point) in palette_points.iter().enumerate() {
-        kdtree.add(point, i as u64);
-    }
-    println!("Palette loaded in {:?}", start.elapsed());
-
-    // Ensure we have a valid input path
-    let input_path = args.input.as_ref().context("No input file provided")?;
-
-    let mut img = ImageReader::open(input_path)?
-        .with_guessed_format()?
-        .decode()?
-        .into_rgb8();
-    
-    img = resize_to_nearest_64(img);
-    
-    // Extract unique colors from the image
-    let unique: HashSet<(u8, u8, u8)> = img.pixels().map(|p| (p[0], p[1], p[2])).collect();
-
-    // In parallel, map each unique color to its nearest palette color
-    let mapping: HashMap<_, _> = unique.into_par_iter().map(|col| {
-        let query = [col.0 as f64, col.1 as f64, col.2 as f64];
-        let nn: kiddo::NearestNeighbour<f64, u64> = kdtree.nearest_one::<SquaredEuclidean>(&query);
-        let idx = nn.item as usize;
-        let pal = palette_points[idx];
-        let new = ((pal[0] as u8), (pal[1] as u8), (pal[2] as u8));
-        (col, (palette_ids[idx].clone(), new))
-    }).collect();
-
-    // Replace each pixel with its nearest palette color in parallel
-    img.as_mut().par_chunks_mut(3).for_each(|pixel| {
-        let key = (pixel[0], pixel[1], pixel[2]);
-        if let Some((_, new)) = mapping.get(&key) {
-            pixel[0] = new.0;
-            pixel[1] = new.1;
-            pixel[2] = new.2;
-        }
-    });
-    println!("Image processed in {:?}", start.elapsed());
-
-    // Save the processed image
-    img.save("output.jpg").expect("Failed to save output.jpg");
-    println!("Image saved in {:?}", start.elapsed());
-
-    // Update map_data JSON
-    let mut map_data: Value = fs::read_to_string(&args.map_data)
-        .with_context(|| format!("Failed to read map data file: {}", args.map_data))?
-        .parse()
-        .context("JSON parse error")?;
-
-    // tileMap set here
-    if let Some(tile_map) = map_data.get_mut("tileMap").and_then(|v| v.as_array_mut()) {
-        for id in mapping.values().map(|(id, _)| id.clone()).collect::<HashSet<_>>() {
-            tile_map.push(json!(id));
-        }
-    } else {
-        eprintln!("tileMap array not found in JSON");
-    }
-    
-    // Run-length Encoding for the tileArray and tileAmounts
-    // Don't ask me how it works I don't know
-    let (w, h) = img.dimensions();
-    let tmap: Vec<_> = map_data["tileMap"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .filter_map(|v| v.as_str().map(|s| s.to_string()))
-        .collect();
-    let pidx = tmap
-        .iter()
-        .enumerate()
-        .map(|(i, id)| (id, i))
-        .collect::<HashMap<_, _>>();
-    let rgb_to_id = mapping.iter().fold(HashMap::new(), |mut m, (_, (id, col))| {
-        m.insert(*col, id);
-        m
-    });
-    let (tile_array, tile_amounts): (Vec<_>, Vec<_>) = (0..h).rev()
-        .map(|y| {
-            (0..w).fold((Vec::new(), Vec::new()), |(mut tiles, mut counts), x| {
-                let p = img.get_pixel(x, y).0;
-                let tuple = (p[0], p[1], p[2]);
-                let idx = *pidx
-                    .get(rgb_to_id.get(&tuple).expect("Color not found"))
-                    .expect("ID not in palette index");
-                if tiles.last() == Some(&idx) {
-                    *counts.last_mut().unwrap() += 1;
-                } else {
-                    tiles.push(idx);
-                    counts.push(1);
-                }
-                (tiles, counts)
-            })
-        })
-        .unzip();
-
-    map_data["height"] = json!(img.height() / 64);
-    map_data["width"] = json!(img.width() / 64);
-    map_data["tileArray"] = json!(tile_array);
-    map_data["tileAmounts"] = json!(tile_amounts);
-
-    //Process our World Laws and Append them to the list
-    let laws = fs::read_to_string(&args.world_laws)?;
-    let list = {
-        if let Some(list) = map_data.get_mut("worldLaws")
-            .and_then(|wl| wl.get_mut("list"))
-            .and_then(Value::as_array_mut) {
-            list
-        } else {
-            map_data["worldLaws"] = json!({ "list": [] });
-            map_data["worldLaws"]["list"].as_array_mut().unwrap()
-        }
-    };
-    
-    list.extend(
-        laws.lines()
-            .filter_map(|l| l.split_once(' '))
-            .map(|(k, v)| {
-                let k = k.trim();
-                if v.trim().eq_ignore_ascii_case("true") {
-                    json!({ "name": k })
-                } else {
-                    json!({ "name": k, "boolVal": false })
-                }
-            })
-    );
-    
-
-    // Optionally process freeze_map image to add frozen_tiles to map_data
-    if let Some(freeze_map_path) = &args.freeze_map {
-        println!("Processing freeze map: {}", freeze_map_path);
-        let freeze_img = open(freeze_map_path)
-            .with_context(|| format!("Failed to open freeze map: {}", freeze_map_path))?
-            .into_rgb8();
-        let mut frozen_tiles = Vec::new();
-        // Record the index of every white pixel (RGB == 255,255,255)
-        for (i, pixel) in freeze_img.pixels().enumerate() {
-            if pixel[0] == 255 && pixel[1] == 255 && pixel[2] == 255 {
-                frozen_tiles.push(i as u32);
-            }
-        }
-        map_data["frozen_tiles"] = json!(frozen_tiles);
-        println!("Frozen tiles added: {}", frozen_tiles.len());
-    }
-
-    // Serialize JSON to string and compress to output file
-    let json_string =
-        serde_json::to_string_pretty(&map_data).expect("Failed to serialize map_data JSON");
-
-    println!("JSON updated in {:?}", start.elapsed());
-
-    compress_to_wbox(&json_string, &args.output)
-        .with_context(|| format!("Failed to compress output to: {}", args.output))?;
-    println!("Compression successful. Output written to {}", args.output);
-
-    println!("Total execution time: {:?}", start.elapsed());
-
-    Ok(())
-}
-
-
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, stdin, BufWriter, Cursor, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use image::{open, codecs::jpeg::JpegEncoder, imageops::{self, resize}, DynamicImage, ImageBuffer, ImageFormat, Pixel, Rgb, RgbImage, ImageReader};
+use herzmapper::{
+    apply_grid, color::{adjust_hsv, blend_linear}, contour, compress_counted, compress_to_wbox_within, compress_wbox_to_vec, denoise, hex_to_rgb, iso, match_distance, match_pixels, noise, parse_hex, quality,
+    read_wbox, rle_decode, rle_encode, sheet, simplify, text::clean_lines, tiers, tiled::{self, TiledTile}, unique_colors, wbox, write_atomic, Budget, ColorSpace, CompressionLevel, Container,
+    Layer, Lut, Palette, QuantizeOptions, TieredPalette, WboxDocument, DEFAULT_CSV_COLUMNS,
+};
+use serde_json::{json, Value};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+mod manifest;
+mod remote;
+
+// Progress messages go to stdout, or to stderr when stdout carries the map itself (--output-base64)
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Time and output size limits of the whole run, from --max-time and --max-output-bytes
+static BUDGET: OnceLock<Budget> = OnceLock::new();
+
+fn budget() -> &'static Budget {
+    BUDGET.get_or_init(Budget::unlimited)
+}
+
+// Aborts the run when it went over --max-time, `phase` being the work that just finished
+fn check_budget(phase: &str) -> Result<()> {
+    Ok(budget().check_time(phase)?)
+}
+
+// Command-line arguments for the program
+#[derive(Parser, Clone)]
+#[command(name = "Image Processor")]
+#[command(about = "Processes an image using a color palette and compresses output", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Paths to the input image files (ex: "images/example.png"), several are converted as a batch. Empty when
+    // the jobs come from --manifest, --layers-dir or --generate, or when only --build-lut runs
+    #[arg(value_name = "IMAGE_FILE", help = "Specify the input image file(s) (ex: images/example.png)")]
+    input: Vec<String>,
+
+    // Optional JSON or TOML file listing conversion jobs, each with its own input and overrides
+    #[arg(long, value_name = "MANIFEST_FILE", conflicts_with = "input", help = "Convert every job listed in a JSON or TOML manifest file")]
+    manifest: Option<String>,
+
+    // Optional directory of layer images named by convention (terrain.png, freeze.png, ...)
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["input", "manifest"], help = "Convert a directory of layer images named terrain.png, freeze.png, river.png, water.png, depth.png, height.png and objects.png")]
+    layers_dir: Option<String>,
+
+    // One auxiliary image whose channels are layers, ex: freeze in red, height in green
+    #[arg(long, value_name = "IMAGE", help = "Auxiliary image whose channels drive several layers, assigned by --data-channels")]
+    data_image: Option<String>,
+    #[arg(long, value_name = "CHANNEL=LAYER", value_delimiter = ',', default_value = "r=freeze,g=height,b=temperature", requires = "data_image", help = "Comma-separated channel (r, g, b, a) to layer (freeze, height, depth, river, water, temperature, none) assignments of --data-image")]
+    data_channels: Vec<String>,
+
+    // Procedural terrain instead of a source image, ex: "512x256" tiles
+    #[arg(long, value_name = "WxH", value_parser = parse_generate_size, conflicts_with_all = ["input", "manifest", "layers_dir", "auto_select_palette"], help = "Generate a W by H tile world from --seed noise instead of converting an image (ex: 512x256)")]
+    generate: Option<(u32, u32)>,
+
+    // Which palette id each band of generated heights becomes, ex: "deep_ocean:0.35,sand:0.4,soil_low:1"
+    #[arg(long, value_name = "ID:LEVEL", value_delimiter = ',', requires = "generate", help = "Comma-separated palette ids with the highest generated height (0-1) each covers, lowest first. Defaults to the palette's ids in file order, in equal bands")]
+    biomes: Vec<String>,
+
+    // Size of the largest generated landmasses
+    #[arg(long, value_name = "TILES", default_value_t = 256.0, requires = "generate", help = "Size in tiles of the largest features of a --generate world")]
+    noise_scale: f64,
+
+    // Path(s) to the palette files containing color mappings (ex: "palettes/all.txt")
+    // Repeat the flag or separate with commas to produce one output per palette
+    #[arg(short, long, value_name = "PALETTE_FILE", default_value = "palettes/no-special.txt", value_delimiter = ',', action = clap::ArgAction::Append, help = "Specify the color palette file(s) (ex: palettes/all.txt). Repeat to compare several palettes")]
+    palette: Vec<String>,
+
+    // Reads "#RRGGBBAA" palette colors and writes their alpha into the map as tileAlpha
+    #[arg(long, help = "Accept 8-digit #RRGGBBAA palette colors and write the alpha of every tileMap id into the map as tileAlpha")]
+    palette_alpha: bool,
+
+    // Palette given directly as "id #hex" entries separated by semicolons, instead of a file
+    #[arg(long, value_name = "ENTRIES", conflicts_with = "palette", help = "Use a palette given inline as semicolon-separated \"id #hex\" entries (ex: \"grass #3a5;water #24f\")")]
+    palette_inline: Option<String>,
+
+    // Ordered tiers picked by brightness instead of nearest color, for clean bands from heightmap-like sources
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["palette", "palette_inline", "auto_select_palette", "generate", "lut", "build_lut", "variation_k", "merge_threshold"], help = "Match each pixel's luminance to the first tier of FILE (one \"id #RRGGBB maxBrightness\" per line) it doesn't exceed, instead of the nearest palette color")]
+    tiered_palette: Option<String>,
+
+    // Optional tiles (lava, grey goo, pits, explosives) kept out of the default palette, merged in on request
+    #[arg(long, conflicts_with = "tiered_palette", help = "Add the special tiles of --special-palette after the palette's own entries")]
+    include_special: bool,
+    #[arg(long, value_name = "FILE", default_value = "palettes/special.txt", help = "Palette of special tiles added by --include-special")]
+    special_palette: String,
+
+    // Brightness where the tiered palette's first (water) tier ends, picked from the histogram or given
+    #[arg(long, requires = "tiered_palette", help = "Pick the sea level, where the first tier of --tiered-palette ends, from the input's brightness histogram")]
+    auto_sea_level: bool,
+    #[arg(long, value_name = "0-100", requires = "auto_sea_level", value_parser = parse_percent, help = "Make --auto-sea-level put this percentage of the pixels under water instead of splitting at the histogram's valley")]
+    sea_percentile: Option<f64>,
+    #[arg(long, value_name = "0-255", requires = "tiered_palette", help = "Sea level brightness where the first tier of --tiered-palette ends, overriding --auto-sea-level")]
+    sea_level: Option<u8>,
+
+    // Columns of a spreadsheet palette holding the ids and colors, found by their header names
+    #[arg(long, num_args = 2, value_names = ["ID_COLUMN", "HEX_COLUMN"], help = "Header names of the id and color columns of .csv palettes [default: id hex]")]
+    csv_columns: Vec<String>,
+
+    // Palette entries this close to an earlier one are merged into it when the palette is loaded
+    #[arg(long, value_name = "N", help = "Merge palette colors within N squared distance (in --color-space) of an earlier one into it, keeping the first id")]
+    merge_threshold: Option<f64>,
+
+    // Directory of candidate palettes, the one closest to the input's colors is used
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["palette", "palette_inline"], help = "Use the palette in DIR whose colors are closest to the input's, printing the ranking of all of them")]
+    auto_select_palette: Option<String>,
+
+    // Path to the JSON map data file. Defaults to "map_data.json" if not provided
+    #[arg(short, long = "map-data", default_value = "map_data.json", value_name = "MAP_JSON", help = "Specify the JSON map data file")]
+    map_data: String,
+    #[arg(long, help = "Start an empty tileMap when the map data file has none, instead of failing")]
+    create_missing: bool,
+
+    // Output file where the processed data will be saved. Defaults to "map.wbox"
+    #[arg(short, long, default_value = "map.wbox", value_name = "OUTPUT_FILE", help = "Specify the output file name")]
+    output: String,
+
+    // Prints the compressed map as base64 to stdout instead of writing the .wbox file
+    #[arg(long, help = "Print the compressed map as one base64 line on stdout instead of writing the output file. Progress messages go to stderr")]
+    output_base64: bool,
+
+    // The game reads compact and indented JSON alike, compact JSON is smaller even after compression
+    #[arg(long, overrides_with = "pretty_json", help = "Write the map JSON without indentation or line breaks, for a smaller .wbox [default]")]
+    compact_json: bool,
+    #[arg(long, overrides_with = "compact_json", help = "Indent the map JSON instead of writing it compact, for reading the decompressed map by hand")]
+    pretty_json: bool,
+
+    // Packs the map and its side outputs into one zip instead of loose files
+    #[arg(long, value_name = "PATH.zip", conflicts_with = "output_base64", help = "Write the map, the preview and any legend or extra images requested into one zip archive at PATH instead of loose files")]
+    bundle: Option<String>,
+
+    // Compression of the output, zstd (behind the zstd cargo feature) is only for archives the game can't load
+    #[arg(long, value_enum, default_value_t = Container::Zlib, value_name = "FORMAT", help = "Compression of the written map: zlib (the game's .wbox format) or, when built with the zstd feature, zstd for archives the game can't load")]
+    container: Container,
+    #[arg(long, value_enum, default_value_t = CompressionLevel::Fast, value_name = "LEVEL", help = "Compression effort for the written map: fast, default or best (smallest, slowest)")]
+    compression: CompressionLevel,
+    // Measures the compressed size in memory instead of writing the map
+    #[arg(long, conflicts_with = "output_base64", help = "Compress the map in memory at the chosen --container and --compression and print its size and ratio instead of writing the output file")]
+    estimate_size: bool,
+
+    // Limits for hosted use, a run going over either is aborted with an error instead of finishing
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, help = "Abort when the run takes longer than SECONDS, checked between phases and while writing the output")]
+    max_time: Option<Duration>,
+    #[arg(long, value_name = "N", help = "Abort when a compressed map grows past N bytes, checked while it is written")]
+    max_output_bytes: Option<u64>,
+
+    // Records how the run was configured, so a good result can be reproduced later
+    #[arg(long, value_name = "PATH", help = "Write the full effective command line, defaults included, to PATH so the run can be replayed")]
+    log_command: Option<String>,
+    #[arg(long, help = "Store the full effective command line in the map JSON under _herzmapper.command")]
+    embed_command: bool,
+    // Which version made the map and when, left out for byte-reproducible outputs
+    #[arg(long, overrides_with = "no_stamp", help = "Store the tool name, version and a UTC timestamp in the map JSON under _herzmapper [default]")]
+    stamp: bool,
+    #[arg(long, overrides_with = "stamp", help = "Leave out the _herzmapper version stamp, so the same run writes the same bytes")]
+    no_stamp: bool,
+    // The effective command line, filled in from the parsed matches
+    #[arg(skip)]
+    command_line: String,
+
+    // Template keys this run leaves exactly as they are, or the only ones it may change
+    #[arg(long, value_name = "KEYS", value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(TEMPLATE_KEYS), help = "Comma-separated map_data keys kept exactly as in the template")]
+    preserve: Vec<String>,
+    #[arg(long, value_name = "KEYS", value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(TEMPLATE_KEYS), conflicts_with = "preserve", help = "Comma-separated map_data keys this run may change, every other one is kept as in the template")]
+    replace_only: Option<Vec<String>>,
+
+    // Optional path to a world laws file (ex: worldlaws/default.txt)
+    #[arg(short, long, value_name = "WORLD_LAWS_FILE",  default_value = "worldlaws/default.txt", help = "Path to a world laws .txt file")]
+    world_laws: String,
+
+    // Single laws set on the command line, ex: for sweeps over one setting without editing the file
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_law, help = "Set one world law after the --world-laws file, replacing any entry of that name: true, false, a whole number or a decimal, read like the file's values. Repeatable")]
+    law: Vec<(String, String)>,
+    // Known law names, so a mistyped --law is reported
+    #[arg(long, value_name = "FILE", help = "File of known world law names, one per line (a world laws file works), to warn about unknown --law names")]
+    law_names: Option<String>,
+
+    // Map metadata written into the template's mapStats, so batch outputs don't need their own templates
+    #[arg(long, value_name = "NAME", help = "Map name shown in the game (mapStats.name)")]
+    map_name: Option<String>,
+
+    #[arg(long, value_name = "TEXT", help = "Map description shown in the game (mapStats.description)")]
+    map_description: Option<String>,
+
+    #[arg(long, value_name = "NAME", help = "Author of the map, written as the save's player name (mapStats.player_name)")]
+    author: Option<String>,
+
+    // Laws scoped to the masked part of the map. The map format has no such field, so this always errors
+    #[arg(long, num_args = 2, value_names = ["MASK_IMAGE", "WORLD_LAWS_FILE"], help = "Apply world laws to the white part of a mask only (not supported by the map format, reported as an error)")]
+    region_laws: Option<Vec<String>>,
+
+    // Optional freeze map images: white pixels in any of them will be marked as frozen
+    #[arg(short, long, value_name = "FREEZE_MAP_IMAGE", action = clap::ArgAction::Append, help = "Specify an optional freeze map image file (ex: images/frozen.png). Repeat to freeze the union of several masks")]
+    freeze_map: Vec<String>,
+
+    // Optional river mask: every non-black pixel becomes a river tile
+    #[arg(long, value_name = "RIVER_MAP_IMAGE", help = "Specify an optional river mask image where non-black pixels become river tiles")]
+    river_map: Option<String>,
+
+    // Tile id written wherever the river mask is set
+    #[arg(long, value_name = "TILE_ID", default_value = "shallow_waters", help = "Tile id used for river tiles")]
+    river_id: String,
+
+    // Thins river strokes down to single-tile lines before placing them
+    #[arg(long, help = "Thin thick river strokes into single-tile rivers")]
+    thin_rivers: bool,
+
+    // Water depth tiles from shallow to deep (ex: "shallow_waters,close_ocean,deep_ocean")
+    #[arg(long, value_name = "IDS", value_delimiter = ',', help = "Comma-separated tile ids from shallow to deep that the water region is split into")]
+    depth_tiles: Vec<String>,
+
+    // Depth values (0-255) where each deeper tile starts, evenly spaced when not given
+    #[arg(long, value_name = "VALUES", value_delimiter = ',', help = "Ascending depth values (0-255) where each next --depth-tiles id starts, one fewer than the tiles. Evenly spaced by default")]
+    depth_thresholds: Vec<u8>,
+
+    // Tile ids forming the water region that gets split into depth tiles
+    #[arg(long, value_name = "IDS", value_delimiter = ',', default_value = "shallow_waters,close_ocean,deep_ocean", help = "Comma-separated tile ids that make up the water region split by --depth-tiles")]
+    water_ids: Vec<String>,
+
+    // Optional water mask: non-black pixels form the water region instead of the --water-ids tiles
+    #[arg(long, value_name = "WATER_MASK_IMAGE", help = "Optional mask image whose non-black pixels form the water region instead of --water-ids")]
+    water_mask: Option<String>,
+
+    // Optional depth image: brighter pixels are deeper. Without it darker source pixels are deeper
+    #[arg(long, value_name = "DEPTH_MAP_IMAGE", help = "Optional depth image where brighter pixels are deeper, instead of using the source image's darkness")]
+    depth_map: Option<String>,
+
+    // Optional height images: brighter pixels are higher, so the lowest water is the deepest.
+    // Several are combined by taking the highest value of each pixel
+    #[arg(long, value_name = "HEIGHT_MAP_IMAGE", action = clap::ArgAction::Append, conflicts_with = "depth_map", help = "Optional height image where brighter pixels are higher, used as an inverted --depth-map. Repeat to combine several by their maximum")]
+    height_map: Vec<String>,
+
+    // Optional resource image: the brightness of each pixel is the amount of --resource-name on that tile
+    #[arg(long, value_name = "RESOURCE_MAP_IMAGE", requires = "resource_name", help = "Optional grayscale image of a resource amount per tile, written as resourceArray/resourceAmounts")]
+    resource_map: Option<String>,
+    #[arg(long, value_name = "NAME", help = "Resource the --resource-map amounts are of, written as resourceName (ex: mana)")]
+    resource_name: Option<String>,
+    #[arg(long, value_name = "N", default_value_t = 255, help = "Amount of a white --resource-map pixel, gray levels scale linearly from 0 for black")]
+    resource_max: u32,
+
+    // Optional marker image: pixels of an object color place that object
+    #[arg(long, value_name = "OBJECTS_IMAGE", requires = "object_colors", help = "Marker image whose object colors place objects into mapObjects")]
+    objects: Option<String>,
+
+    // Object ids and their marker colors, one "id #RRGGBB" per line like a palette
+    #[arg(long, value_name = "OBJECT_COLORS_FILE", help = "File pairing each object id with its marker color, one \"id #RRGGBB\" per line")]
+    object_colors: Option<String>,
+
+    // How marker pixels become objects
+    #[arg(long, value_enum, default_value_t = ObjectPlacement::Centroid, value_name = "PLACEMENT", help = "Place one object per connected region of a color, or one per pixel")]
+    object_placement: ObjectPlacement,
+
+    // Tile id for transparent pixels of RGBA inputs (ex: "deep_ocean")
+    #[arg(long, value_name = "TILE_ID", help = "Tile id placed on transparent pixels instead of color-matching them")]
+    background_id: Option<String>,
+
+    // How far a color may be forced into the palette before it becomes the background tile
+    #[arg(long, value_enum, default_value_t = SnapPolicy::Nearest, value_name = "POLICY", help = "Force every color to its nearest palette color (nearest), only up to --max-distance (limited), or only exact colors (exact). Rejected colors take the background tile")]
+    snap_policy: SnapPolicy,
+
+    // CIELAB distance (ΔE) up to which --snap-policy limited keeps a match
+    #[arg(long, value_name = "DELTA_E", default_value_t = 10.0, help = "Largest CIELAB distance (ΔE) of a kept match with --snap-policy limited")]
+    max_distance: f64,
+
+    // Shoreline tile ids for partly transparent pixels, from the most transparent to the most opaque
+    #[arg(long, value_name = "IDS", value_delimiter = ',', help = "Comma-separated tile ids for partly transparent pixels of RGBA inputs, from the most transparent to the most opaque (ex: shallow_waters,sand)")]
+    shore_tiles: Vec<String>,
+
+    // Alpha values (0-255) where each shore tile starts, evenly spaced when not given
+    #[arg(long, value_name = "VALUES", value_delimiter = ',', help = "Ascending alpha values (0-255) where each --shore-tiles id starts, one per tile. Lower alpha is background. Evenly spaced by default")]
+    shore_thresholds: Vec<u8>,
+
+    // Pixels of exactly this color keep the template's existing tile (ex: "#FF00FF")
+    #[arg(long, value_name = "HEX", help = "Pixels of exactly this color keep the template map's existing tile")]
+    transparent_color: Option<String>,
+
+    // Colors whose near matches are kept out of the color matching, ex: antialiased black outlines
+    #[arg(long, num_args = 2, value_names = ["HEX", "TOLERANCE"], action = clap::ArgAction::Append, help = "Pixels within TOLERANCE (0-255 per channel) of HEX take --ignore-fallback or the template's tile instead of a color match. Repeatable")]
+    ignore_color: Vec<String>,
+
+    // Tile for ignored pixels, the template's existing tile when not given
+    #[arg(long, value_name = "TILE_ID", help = "Tile id placed on --ignore-color pixels instead of keeping the template's tile")]
+    ignore_fallback: Option<String>,
+
+    // Color space used to find the nearest palette color
+    #[arg(long, value_enum, default_value_t = ColorSpace::Rgb, value_name = "SPACE", help = "Color space used for nearest color matching")]
+    color_space: ColorSpace,
+
+    // Keeps extreme colors from matching whichever palette color sits on the nearest edge of its range
+    #[arg(long, help = "Pull colors outside the palette's range toward its centroid before finding the nearest palette color")]
+    clamp_gamut: bool,
+
+    // Palette ids that are only placed where the source color matches them exactly
+    #[arg(long, value_name = "IDS", value_delimiter = ',', help = "Comma-separated palette ids that are never chosen as a nearest match, only on exact color matches")]
+    lock_ids: Vec<String>,
+
+    // Picks each pixel at random among its K nearest palette colors for natural mottling
+    #[arg(long, value_name = "K", default_value_t = 1, help = "Pick each pixel at random among its K nearest palette colors, weighted by inverse distance")]
+    variation_k: usize,
+
+    // Seed for --variation-k, the same seed always gives the same map
+    #[arg(long, value_name = "SEED", default_value_t = 0, help = "Random seed used by --variation-k")]
+    seed: u64,
+
+    // Precomputes the nearest palette color of a sampled RGB cube into this file
+    #[arg(long, value_name = "LUT_FILE", help = "Build a lookup table of nearest palette colors for the palette and matching options, then convert any inputs given")]
+    build_lut: Option<String>,
+
+    // Distance between LUT samples on every channel
+    #[arg(long, value_name = "STEP", default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..), help = "Distance between LUT samples per channel, smaller is more accurate but larger")]
+    lut_step: u8,
+
+    // Answers nearest color matches from a prebuilt LUT instead of the kd-tree
+    #[arg(long, value_name = "LUT_FILE", conflicts_with = "variation_k", help = "Use a lookup table from --build-lut instead of searching the palette")]
+    lut: Option<String>,
+
+    // Approximate matching through a coarse LUT built in memory, for quick previews with large palettes
+    #[arg(long, conflicts_with_all = ["lut", "variation_k", "tiered_palette"], help = "Match colors through a coarse lookup table (about 5 bits per channel) built from the palette, faster but less accurate than the exact search")]
+    fast_match: bool,
+
+    // Optional legend file describing what each tileArray index means
+    #[arg(long, value_name = "LEGEND_JSON", help = "Write a JSON legend pairing each tile index with its id and color")]
+    legend: Option<String>,
+
+    // Optional palette file of the entries the map ended up using, ex: to trim a large palette
+    #[arg(long, value_name = "FILE", help = "Write the palette entries that appear in the finished map to FILE, as an \"id #RRGGBB\" palette")]
+    export_used_palette: Option<String>,
+
+    // Optional overview image with a labelled thumbnail of every converted map
+    #[arg(long, value_name = "CONTACT_SHEET_IMAGE", help = "Write a contact sheet with a thumbnail of every converted map")]
+    contact_sheet: Option<String>,
+
+    // Number of thumbnails per row on the contact sheet
+    #[arg(long, value_name = "COLUMNS", default_value_t = 4, help = "Number of columns on the contact sheet")]
+    contact_columns: u32,
+
+    // Path of the preview image, its extension picks the format. PNG keeps the exact tile colors.
+    // Without the flag the preview takes the input's format, see `preview_path`
+    #[arg(long, value_name = "PREVIEW_IMAGE", default_value = "output.png", help = "Preview image path, the extension picks the format (PNG is lossless). Defaults to output.<input's extension>, or output.png for formats that can't be written")]
+    preview_image: String,
+    // Whether --preview-image was given, filled in from the parsed matches
+    #[arg(skip)]
+    preview_image_given: bool,
+
+    // JPEG quality of the preview and contact sheet when they are saved as JPEG
+    #[arg(long, value_name = "1-100", value_parser = clap::value_parser!(u8).range(1..=100), help = "JPEG quality (1-100) for a .jpg preview or contact sheet")]
+    preview_quality: Option<u8>,
+
+    // Scales the preview back to the input's own size so it lines up with the source art
+    #[arg(long, help = "Save the preview at the original input size instead of the map's tile grid size")]
+    preview_original_size: bool,
+
+    // Shaded 2.5D view of the map raised by the height maps, an approximation for judging elevation
+    #[arg(long, value_name = "PATH", help = "Save an approximate shaded 2.5D preview raised by --height-map (or an inverted --depth-map)")]
+    iso_preview: Option<String>,
+
+    // Topographic lines over the preview, from the same heights as the isometric preview
+    #[arg(long, value_name = "INTERVAL", value_parser = clap::value_parser!(u8).range(1..), help = "Draw elevation contour lines on the preview every INTERVAL height levels (1-255) of --height-map (or an inverted --depth-map)")]
+    contours: Option<u8>,
+
+    // Small mode-based downsample of the tiles, for map listing icons
+    #[arg(long, num_args = 2, value_names = ["PATH", "SIZE"], help = "Save a minimap at most SIZE pixels on its longest side, each pixel the most common tile color of its area")]
+    minimap: Vec<String>,
+
+    // Where the palette fits the source poorly, from each tile's distance to its matched color
+    #[arg(long, value_name = "PATH", help = "Save a heatmap of how far each pixel's color is from its matched palette color, green for exact to red for the farthest")]
+    quality_map: Option<String>,
+
+    // How much every pixel of the final preview differs from the source at the same resolution
+    #[arg(long, value_name = "PATH", help = "Save a grayscale image of how far each preview pixel's color is from the source pixel, brighter for larger changes")]
+    delta_image: Option<String>,
+
+    // Image whose pixel colors encode the tileMap index of every tile, instead of its display color
+    #[arg(long, value_name = "PATH", help = "Save a lossless image where each pixel's color is the tile's tileMap index (R low byte, G middle byte, B high byte)")]
+    index_image: Option<String>,
+
+    // Tiled editor map of the grid, with a tileset image of color swatches saved next to it
+    #[arg(long, value_name = "PATH", help = "Write the tile grid as a Tiled JSON map, plus its tileset of color swatches as PATH's name with .tileset.png")]
+    export_tiled: Option<String>,
+
+    // Only saves the palette preview, without reading the template or writing a .wbox
+    #[arg(long, conflicts_with = "transparent_color", help = "Only save the preview image, skipping the map template and the .wbox output")]
+    preview_only: bool,
+
+    // Tile ids that should never be frozen, checked against the freeze map
+    #[arg(long, value_name = "IDS", value_delimiter = ',', default_value = "lava0,lava1,lava2,lava3,soil_low:desert_low,soil_high:desert_high,soil_low:infernal_low,soil_high:infernal_high", help = "Comma-separated tile ids that are reported when the freeze map freezes them")]
+    hot_ids: Vec<String>,
+
+    // Turns consistency warnings between layers into errors
+    #[arg(long, help = "Fail instead of warning when layers contradict each other (ex: frozen lava) or the input is a single color")]
+    strict: bool,
+
+    // Turns the tile count check after run-length encoding into a hard error instead of a warning
+    #[arg(long, help = "Fail instead of warning when the encoded tileAmounts don't cover every pixel exactly")]
+    audit: bool,
+
+    // Reports how well the tiles run-length encode, to spot source art that produces tiny runs
+    #[arg(long, help = "Print the number of runs, the average and longest run length and the share of single-tile runs")]
+    rle_stats: bool,
+
+    // Splits the RLE rows into bands for loaders that stream the map instead of reading one giant array
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), help = "Write tileArray and tileAmounts as bands of N rows under a chunks key, for streaming map loaders")]
+    chunked_tiles: Option<u32>,
+
+    // Number of batch inputs converted at the same time
+    #[arg(long, value_name = "N", default_value_t = 1, help = "Convert up to N batch inputs concurrently, sharing the CPU cores between them")]
+    batch_concurrency: usize,
+
+    // Mirrors the input and every auxiliary image before encoding. The game's origin is the
+    // bottom-left tile, and the image's top row is written last so "none" keeps the map upright
+    #[arg(long, value_enum, default_value_t = Flip::None, value_name = "FLIP", help = "Flip the input and auxiliary images before encoding")]
+    flip: Flip,
+
+    // How an input that isn't a multiple of 64 is fitted to the tile grid
+    #[arg(long, value_enum, default_value_t = Snap::Scale, value_name = "MODE", help = "Fit inputs to the 64-tile grid by scaling, padding with the background tile or cropping")]
+    snap: Snap,
+
+    // Repeats the source image in a grid before resizing, ex: "2x3" for 2 rows of 3 copies
+    #[arg(long, value_name = "RxC", value_parser = parse_tiling, help = "Repeat the input and auxiliary images R rows by C columns before resizing (ex: 2x3)")]
+    tile_source: Option<(u32, u32)>,
+
+    // Mirrors every other copy of a tiled source so the seams line up
+    #[arg(long, requires = "tile_source", help = "Mirror alternate copies of a tiled source so neighbouring edges match")]
+    mirror_seams: bool,
+
+    // Map widths in tiles to convert the input at, each with its own outputs (ex: "128,256,512")
+    #[arg(long, value_name = "WIDTHS", value_delimiter = ',', value_parser = parse_scale, help = "Convert the input at each map width in tiles (multiples of 64, at least 128), with outputs named after the size")]
+    scales: Vec<u32>,
+
+    // Frame of one tile around the map, ex: an impassable border of mountains
+    #[arg(long, num_args = 2, value_names = ["TILE_ID", "THICKNESS"], help = "Overwrite a frame THICKNESS tiles wide along the map's edges with TILE_ID after every other layer. Border tiles are never frozen")]
+    border: Vec<String>,
+
+    // Loaders may limit how many runs a map holds, noisy sources can go over
+    #[arg(long, value_name = "N", help = "Fail when the map's RLE holds more than N runs in total, see --auto-simplify")]
+    max_runs: Option<usize>,
+    #[arg(long, requires = "max_runs", help = "Remove ever larger speckles until the map is within --max-runs instead of failing")]
+    auto_simplify: bool,
+
+    // Exact map size in 64-tile cells, whatever the input's aspect ratio, ex: "4x3" for 256x192 tiles
+    #[arg(long, value_name = "WxH", value_parser = parse_cells, conflicts_with_all = ["scales", "generate"], help = "Stretch the input and auxiliary images to exactly W by H map cells of 64 tiles (ex: 4x3), warning when that distorts the aspect ratio")]
+    force_tiles: Option<(u32, u32)>,
+
+    // Second image mixed into the input before matching, ex: a winter version of the same terrain
+    #[arg(long, num_args = 2, value_names = ["IMAGE", "FACTOR"], help = "Blend IMAGE into the input in linear light before matching, FACTOR from 0.0 (input only) to 1.0 (IMAGE only)")]
+    blend: Vec<String>,
+
+    // Median filter against compression noise, ex: the 8x8 blocks of JPEG sources
+    #[arg(long, value_name = "RADIUS", value_parser = clap::value_parser!(u32).range(1..=8), help = "Median filter the resized input with this radius (1-8) before matching, to remove JPEG block noise and stray tiles")]
+    denoise: Option<u32>,
+
+    // Color correction of the source, to bring a too dark or washed out image into the palette's range
+    #[arg(long, value_name = "DH,DS,DB", allow_hyphen_values = true, value_parser = parse_hsb, help = "Shift the input's hue by DH degrees and its HSV saturation and brightness by DS and DB percentage points (-100 to 100) before matching, ex: 0,10,15")]
+    hsb: Option<(f64, f64, f64)>,
+
+    // Disables pause before exiting
+    #[arg(short, long, value_name = "NO_PAUSE", default_value_t = true, action = clap::ArgAction::SetFalse, help = "Specify this if you don't want the program to pause before exit")]
+    no_pause: bool,
+}
+
+// Tools that work on existing .wbox files instead of converting an image
+#[derive(Subcommand, Clone)]
+enum Command {
+    // Checks a .wbox file against the format the game expects
+    #[command(about = "Check a .wbox file for problems that would stop it from loading")]
+    Validate {
+        #[arg(value_name = "WBOX_FILE")]
+        file: String,
+    },
+    // Parses a text palette once into the binary format --palette also accepts
+    #[command(about = "Compile a text palette into a binary palette file that loads without parsing")]
+    CompilePalette {
+        #[arg(value_name = "PALETTE_FILE")]
+        palette: String,
+        #[arg(value_name = "OUTPUT_FILE")]
+        output: String,
+        #[arg(long, num_args = 2, value_names = ["ID_COLUMN", "HEX_COLUMN"], help = "Header names of the id and color columns of a .csv palette [default: id hex]")]
+        csv_columns: Vec<String>,
+    },
+    // Summarizes a .wbox file without dumping its JSON
+    #[command(about = "Print the size, tile, world law, frozen tile and object counts of a .wbox file")]
+    Info {
+        #[arg(value_name = "WBOX_FILE")]
+        file: String,
+        // Number of tileMap entries listed
+        #[arg(long, value_name = "N", default_value_t = 10, help = "Number of tileMap entries to list")]
+        entries: usize,
+    },
+    // Compares the tiles of two .wbox files
+    #[command(about = "Show which tiles differ between two .wbox files")]
+    Diff {
+        #[arg(value_name = "OLD_WBOX")]
+        old: String,
+        #[arg(value_name = "NEW_WBOX")]
+        new: String,
+        // Image with the changed tiles highlighted
+        #[arg(short, long, value_name = "DIFF_IMAGE", default_value = "diff.png", help = "Image highlighting the changed tiles in red")]
+        output: String,
+    },
+    // Converts a generated image with a generated palette, to check an install and time the pipeline
+    #[command(about = "Convert a generated image with a generated palette, validate the result and print timings")]
+    Selftest {
+        // Side of the generated square image in tiles
+        #[arg(long, value_name = "TILES", default_value = "1024", value_parser = parse_scale, help = "Side of the generated image in tiles (a multiple of 64, at least 128)")]
+        size: u32,
+    },
+}
+
+// How the pixels of an object marker color become placed objects
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ObjectPlacement {
+    // One object at the center of every connected region
+    Centroid,
+    // One object on every marker pixel
+    Pixel,
+}
+
+// How an image is fitted to a whole number of map cells
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Snap {
+    // Nearest-neighbor resize up to the next multiple of 64
+    Scale,
+    // Extend the right and bottom edges to the next multiple of 64 without resampling
+    Pad,
+    // Trim the right and bottom edges down to the previous multiple of 64
+    Crop,
+}
+
+// Which color matches are forced into the palette
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SnapPolicy {
+    // Every color takes its nearest palette color
+    Nearest,
+    // Colors further than --max-distance from their match take the background tile
+    Limited,
+    // Only exact palette colors are matched, every other color takes the background tile
+    Exact,
+}
+
+// Share of the pixels one color may cover before an input is reported as essentially uniform
+const UNIFORM_SHARE: f64 = 0.99;
+
+// LUT step of --fast-match: 33 samples per channel, about 5 bits, small enough to build in milliseconds
+const FAST_MATCH_STEP: u8 = 8;
+
+// Name of the palette given with --palette-inline, used in messages and output suffixes
+const INLINE_PALETTE: &str = "inline";
+
+// Name of the input of a --generate run in messages, it has no file
+const GENERATED_INPUT: &str = "generated world";
+
+// Characters accepted between a world law's name and its value ("name true", "name=true", "name: true")
+const LAW_SEPARATORS: [char; 4] = [' ', '\t', '=', ':'];
+
+// Splits a world law line into its trimmed name and value, None when there is no separator
+fn split_world_law(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once(LAW_SEPARATORS)?;
+    Some((name.trim(), value.trim_start_matches(LAW_SEPARATORS).trim()))
+}
+
+// The worldLaws entry of a law line or --law: "true" leaves it on, a whole number or a decimal sets its intVal or floatVal
+// (the law staying on), anything else disables it like "false"
+fn world_law(name: &str, value: &str) -> Value {
+    if value.eq_ignore_ascii_case("true") {
+        json!({ "name": name })
+    } else if let Ok(number) = value.parse::<i64>() {
+        json!({ "name": name, "intVal": number })
+    } else if let Some(number) = value.parse::<f64>().ok().filter(|n| n.is_finite()) {
+        json!({ "name": name, "floatVal": number })
+    } else {
+        json!({ "name": name, "boolVal": false })
+    }
+}
+
+// A --law NAME=VALUE, stricter than a file line: a value that isn't a bool or a number is a typo here
+fn parse_law(value: &str) -> Result<(String, String), String> {
+    let (name, law) = split_world_law(value).filter(|(name, _)| !name.is_empty()).ok_or("expected NAME=VALUE, ex: world_law_hunger=false")?;
+    let number = law.parse::<f64>().is_ok_and(f64::is_finite);
+    if !number && !law.eq_ignore_ascii_case("true") && !law.eq_ignore_ascii_case("false") {
+        return Err(format!("{} is not true, false or a number", law));
+    }
+    Ok((name.to_string(), law.to_string()))
+}
+
+// Top-level map_data keys a run may change besides the tile grid itself (tileArray, tileAmounts or chunks,
+// width and height), which always describes the converted image
+const TEMPLATE_KEYS: [&str; 6] = ["tileMap", "worldLaws", "mapStats", "frozen_tiles", "mapObjects", "_herzmapper"];
+
+// The TEMPLATE_KEYS kept as in the template by --preserve or --replace-only
+fn preserved_keys(args: &Args) -> Vec<&'static str> {
+    TEMPLATE_KEYS.into_iter().filter(|key| match &args.replace_only {
+        Some(replace) => !replace.iter().any(|r| r == key),
+        None => args.preserve.iter().any(|p| p == key),
+    }).collect()
+}
+
+// Tile used for padding when no --background-id is given
+const DEFAULT_BACKGROUND_ID: &str = "deep_ocean";
+
+// Mirroring applied to every image before it is encoded
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Flip {
+    None,
+    Vertical,
+    Horizontal,
+    Both,
+}
+
+// Applies the requested flip in place so the main and auxiliary images always stay aligned
+fn apply_flip<P: Pixel + 'static>(img: &mut ImageBuffer<P, Vec<P::Subpixel>>, flip: Flip) {
+    if matches!(flip, Flip::Vertical | Flip::Both) {
+        imageops::flip_vertical_in_place(img);
+    }
+    if matches!(flip, Flip::Horizontal | Flip::Both) {
+        imageops::flip_horizontal_in_place(img);
+    }
+}
+
+// A --max-time limit, a positive number of seconds with fractions allowed
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(format!("{} is not a positive number of seconds", value)),
+    }
+}
+
+// A percentage from 0 to 100, fractions allowed
+fn parse_percent(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("{} is not a percentage from 0 to 100", value)),
+    }
+}
+
+// Parses a map width for --scales, which must be whole cells of at least 2 cells
+fn parse_scale(value: &str) -> Result<u32, String> {
+    let width: u32 = value.trim().parse().map_err(|_| format!("{} is not a width in tiles", value))?;
+    if width < 128 || !width.is_multiple_of(64) {
+        return Err(format!("{} is not a multiple of 64 of at least 128, maps are made of 64-tile cells", width));
+    }
+    Ok(width)
+}
+
+// A --generate size, both sides whole map cells like --scale
+fn parse_generate_size(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value.split_once(['x', 'X']).ok_or("expected WxH in tiles, ex: 512x256")?;
+    Ok((parse_scale(width)?, parse_scale(height)?))
+}
+
+// A --force-tiles size in map cells, at least 2 per side like every snapped map
+fn parse_cells(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value.split_once(['x', 'X']).ok_or("expected WxH in map cells, ex: 4x3")?;
+    let parse = |n: &str| n.trim().parse::<u32>().ok().filter(|&n| (2..=u32::MAX / 64).contains(&n))
+        .ok_or(format!("{} is not a count of at least 2 map cells", n));
+    Ok((parse(width)?, parse(height)?))
+}
+
+// A --hsb adjustment: hue degrees, then saturation and brightness percentage points
+fn parse_hsb(value: &str) -> Result<(f64, f64, f64), String> {
+    let parts: Vec<&str> = value.split(',').map(str::trim).collect();
+    let [hue, saturation, brightness] = parts[..] else {
+        return Err("expected DH,DS,DB, ex: 0,10,15".to_string());
+    };
+    let number = |n: &str| n.parse::<f64>().ok().filter(|n| n.is_finite()).ok_or(format!("{} is not a number", n));
+    let points = |n: &str| number(n).and_then(|p| if (-100.0..=100.0).contains(&p) { Ok(p) } else { Err(format!("{} is not from -100 to 100", n)) });
+    Ok((number(hue)?, points(saturation)?, points(brightness)?))
+}
+
+// Parses a "RxC" tiling into (rows, columns)
+fn parse_tiling(value: &str) -> Result<(u32, u32), String> {
+    let (rows, columns) = value.split_once(['x', 'X']).ok_or("expected RxC, ex: 2x3")?;
+    let parse = |n: &str| n.trim().parse::<u32>().ok().filter(|&n| n > 0).ok_or(format!("{} is not a positive count", n));
+    Ok((parse(rows)?, parse(columns)?))
+}
+
+// Most pixels a tiled source may have, a 16384x16384 image. Anything larger would take gigabytes before
+// resizing or fail to allocate at all
+const MAX_TILED_PIXELS: u64 = 1 << 28;
+
+// Repeats an image rows x columns times. With mirror_seams odd columns are mirrored horizontally
+// and odd rows vertically, so every copy meets its neighbours edge to matching edge
+fn tile_image<P: Pixel + 'static>(img: &ImageBuffer<P, Vec<P::Subpixel>>, (rows, columns): (u32, u32), mirror_seams: bool) -> Result<ImageBuffer<P, Vec<P::Subpixel>>> {
+    let (w, h) = img.dimensions();
+    let too_large = || anyhow::anyhow!(
+        "--tile-source {}x{} of a {}x{} image is too large, a tiled source may have at most {} pixels (16384x16384)",
+        rows, columns, w, h, MAX_TILED_PIXELS
+    );
+    let (width, height) = (w.checked_mul(columns).ok_or_else(too_large)?, h.checked_mul(rows).ok_or_else(too_large)?);
+    if width as u64 * height as u64 > MAX_TILED_PIXELS {
+        return Err(too_large());
+    }
+    Ok(ImageBuffer::from_fn(width, height, |x, y| {
+        let (column, row) = (x / w, y / h);
+        let (mut sx, mut sy) = (x % w, y % h);
+        if mirror_seams && column % 2 == 1 {
+            sx = w - 1 - sx;
+        }
+        if mirror_seams && row % 2 == 1 {
+            sy = h - 1 - sy;
+        }
+        *img.get_pixel(sx, sy)
+    }))
+}
+
+// Tiles and flips a decoded image as requested, the same way for the input and every auxiliary image
+fn prepare_image<P: Pixel + 'static>(mut img: ImageBuffer<P, Vec<P::Subpixel>>, args: &Args) -> Result<ImageBuffer<P, Vec<P::Subpixel>>> {
+    if let Some(tiling) = args.tile_source {
+        img = tile_image(&img, tiling, args.mirror_seams)?;
+    }
+    apply_flip(&mut img, args.flip);
+    Ok(img)
+}
+
+// Opens an auxiliary image (freeze map, river map, ...) tiled and flipped like the input and resized to its tile grid
+fn load_aux_image(path: &str, args: &Args, width: u32, height: u32) -> Result<RgbImage> {
+    let img = match data_channel(args, path) {
+        Some((data_image, channel)) => channel_image(data_image, channel)?,
+        None => open(path).with_context(|| format!("Failed to open auxiliary image: {}", path))?.into_rgb8(),
+    };
+    // A forced size stretches the whole image like the input, without snapping it first
+    let mut img = match args.force_tiles {
+        Some(_) => prepare_image(img, args)?,
+        None => snap_image(prepare_image(img, args)?, args.snap, Rgb([0, 0, 0]))?,
+    };
+    if img.dimensions() != (width, height) {
+        img = resize(&img, width, height, imageops::FilterType::Nearest);
+    }
+    Ok(img)
+}
+
+// Finds objects in a marker image as (x, y, id index), x and y in tile coordinates from the bottom-left
+// like the game. Regions are 4-connected pixels of the same object color, placed at their rounded centroid
+fn place_objects(markers: &RgbImage, colors: &Palette, placement: ObjectPlacement) -> Vec<(u32, u32, usize)> {
+    let (w, h) = markers.dimensions();
+    let object_at = |x: u32, y: u32| {
+        let p = markers.get_pixel(x, y);
+        colors.exact_index((p[0], p[1], p[2]))
+    };
+    let mut visited = vec![false; (w * h) as usize];
+    let mut objects = Vec::new();
+    for (x, y) in (0..h).flat_map(|y| (0..w).map(move |x| (x, y))) {
+        if visited[(y * w + x) as usize] {
+            continue;
+        }
+        let Some(object) = object_at(x, y) else { continue };
+        if placement == ObjectPlacement::Pixel {
+            objects.push((x, h - 1 - y, object));
+            continue;
+        }
+        // Flood fill the region, summing its coordinates for the centroid
+        let (mut sum_x, mut sum_y, mut count) = (0u64, 0u64, 0u64);
+        let mut stack = vec![(x, y)];
+        visited[(y * w + x) as usize] = true;
+        while let Some((cx, cy)) = stack.pop() {
+            sum_x += cx as u64;
+            sum_y += cy as u64;
+            count += 1;
+            let neighbours = [
+                (cx.wrapping_sub(1), cy), (cx + 1, cy), (cx, cy.wrapping_sub(1)), (cx, cy + 1),
+            ];
+            for (nx, ny) in neighbours {
+                if nx < w && ny < h && !visited[(ny * w + nx) as usize] && object_at(nx, ny) == Some(object) {
+                    visited[(ny * w + nx) as usize] = true;
+                    stack.push((nx, ny));
+                }
+            }
+        }
+        let (cx, cy) = ((sum_x as f64 / count as f64).round() as u32, (sum_y as f64 / count as f64).round() as u32);
+        objects.push((cx, h - 1 - cy, object));
+    }
+    objects
+}
+
+// Zhang-Suen thinning, reduces strokes in a row-major mask to lines one tile wide
+fn thin_mask(mask: &mut [bool], width: usize, height: usize) {
+    let at = |mask: &[bool], x: usize, y: usize| mask[y * width + x] as u8;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for step in 0..2 {
+            let mut remove = Vec::new();
+            for y in 1..height.saturating_sub(1) {
+                for x in 1..width.saturating_sub(1) {
+                    if !mask[y * width + x] {
+                        continue;
+                    }
+                    // Neighbours clockwise starting north
+                    let n = [
+                        at(mask, x, y - 1), at(mask, x + 1, y - 1), at(mask, x + 1, y), at(mask, x + 1, y + 1),
+                        at(mask, x, y + 1), at(mask, x - 1, y + 1), at(mask, x - 1, y), at(mask, x - 1, y - 1),
+                    ];
+                    let filled: u8 = n.iter().sum();
+                    let transitions = (0..8).filter(|&i| n[i] == 0 && n[(i + 1) % 8] == 1).count();
+                    let (a, b) = if step == 0 {
+                        (n[0] * n[2] * n[4], n[2] * n[4] * n[6])
+                    } else {
+                        (n[0] * n[2] * n[6], n[0] * n[4] * n[6])
+                    };
+                    if (2..=6).contains(&filled) && transitions == 1 && a == 0 && b == 0 {
+                        remove.push(y * width + x);
+                    }
+                }
+            }
+            changed |= !remove.is_empty();
+            for i in remove {
+                mask[i] = false;
+            }
+        }
+    }
+}
+
+// Checks that the run lengths cover every tile of a `width` x `height` grid exactly once, row by row,
+// returning what is wrong otherwise
+fn audit_tile_amounts(tile_amounts: &[Vec<usize>], width: usize, height: usize) -> Option<String> {
+    let covered: usize = tile_amounts.iter().flatten().sum();
+    let bad_rows = tile_amounts.iter().filter(|row| row.iter().sum::<usize>() != width).count();
+    (covered != width * height || tile_amounts.len() != height || bad_rows > 0).then(|| format!(
+        "tileAmounts cover {} tiles in {} rows ({} of the wrong width) but the image has {} tiles in {} rows",
+        covered, tile_amounts.len(), bad_rows, width * height, height
+    ))
+}
+
+// Resizes an image so each side is rounded up to the next multiple of 64, with a minimum of 128
+// (2 map cells). Sides are handled independently: 1, 64, 65 and 128 all become 128, 129 and 192 become 192
+fn resize_to_nearest_64<P: Pixel + 'static>(img: ImageBuffer<P, Vec<P::Subpixel>>) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = img.dimensions();
+    let new_width = width.div_ceil(64).max(2) * 64;
+    let new_height = height.div_ceil(64).max(2) * 64;
+    resize(&img, new_width, new_height, image::imageops::FilterType::Nearest)
+}
+
+// Fits an image to whole map cells (of at least 2 cells per side). Padding uses the fill pixel,
+// crop and pad never resample so every source pixel stays exactly where it was
+fn snap_image<P: Pixel + 'static>(img: ImageBuffer<P, Vec<P::Subpixel>>, snap: Snap, fill: P) -> Result<ImageBuffer<P, Vec<P::Subpixel>>> {
+    let (width, height) = img.dimensions();
+    match snap {
+        Snap::Scale => Ok(resize_to_nearest_64(img)),
+        Snap::Pad => {
+            let (new_width, new_height) = (width.div_ceil(64).max(2) * 64, height.div_ceil(64).max(2) * 64);
+            if (new_width, new_height) == (width, height) {
+                return Ok(img);
+            }
+            let mut padded = ImageBuffer::from_pixel(new_width, new_height, fill);
+            imageops::replace(&mut padded, &img, 0, 0);
+            Ok(padded)
+        }
+        Snap::Crop => {
+            let (new_width, new_height) = (width / 64 * 64, height / 64 * 64);
+            if new_width < 128 || new_height < 128 {
+                anyhow::bail!("Cannot crop a {}x{} image, cropping needs at least 128x128 pixels", width, height);
+            }
+            Ok(imageops::crop_imm(&img, 0, 0, new_width, new_height).to_image())
+        }
+    }
+}
+
+// Returns the index of a tile id in tile_ids, appending it if the palette doesn't have it
+fn tile_index(tile_ids: &mut Vec<String>, id: &str) -> usize {
+    match tile_ids.iter().position(|t| t == id) {
+        Some(i) => i,
+        None => {
+            tile_ids.push(id.to_string());
+            tile_ids.len() - 1
+        }
+    }
+}
+
+// Values (0-255) where each of `count` bands after the first starts, checked against the
+// given thresholds or spread evenly over 0-255. `kind` names the tiles in errors ("depth", "shore")
+fn band_thresholds(kind: &str, tiles: usize, count: usize, thresholds: &[u8]) -> Result<Vec<u8>> {
+    if thresholds.is_empty() {
+        return Ok((1..=count).map(|k| (256 * k / (count + 1)) as u8).collect());
+    }
+    if thresholds.len() != count {
+        anyhow::bail!("{} {} tiles need {} thresholds, got {}", tiles, kind, count, thresholds.len());
+    }
+    if thresholds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        anyhow::bail!("The {} thresholds must be strictly ascending: {:?}", kind, thresholds);
+    }
+    Ok(thresholds.to_vec())
+}
+
+// A decoded input image at the map's tile grid size
+struct Source {
+    img: RgbImage,
+    // Alpha channel, kept only when transparent pixels become the background tile
+    alpha: Option<Vec<u8>>,
+    // Dimensions of the input before it was resized to the tile grid
+    original_size: (u32, u32),
+}
+
+// One input converted with its own settings, from the command line or a manifest entry
+struct Job {
+    args: Args,
+    input: String,
+    // Inserted into every output name to keep the outputs of several inputs apart
+    suffix: Option<String>,
+    // Set when a manifest entry names its own .wbox, which then only gets the palette suffix
+    own_output: bool,
+}
+
+// Paths of every file written for one converted map
+struct Outputs {
+    wbox: String,
+    preview: String,
+    legend: Option<String>,
+    used_palette: Option<String>,
+    iso_preview: Option<String>,
+    minimap: Option<String>,
+    quality_map: Option<String>,
+    delta_image: Option<String>,
+    index_image: Option<String>,
+    tiled: Option<String>,
+    tiled_tileset: Option<String>,
+    bundle: Option<String>,
+}
+
+impl Outputs {
+    // Output paths from the arguments, with every suffix inserted in order (ex: "map.example.all.wbox")
+    fn new(args: &Args, input: &str, suffixes: &[&str]) -> Outputs {
+        let name = |path: &str| suffixes.iter().fold(path.to_string(), |p, suffix| suffixed_path(&p, suffix));
+        Outputs {
+            wbox: name(&args.output),
+            preview: name(&preview_path(args, input)),
+            legend: args.legend.as_deref().map(name),
+            used_palette: args.export_used_palette.as_deref().map(name),
+            iso_preview: args.iso_preview.as_deref().map(name),
+            minimap: args.minimap.first().map(|path| name(path)),
+            quality_map: args.quality_map.as_deref().map(name),
+            delta_image: args.delta_image.as_deref().map(name),
+            index_image: args.index_image.as_deref().map(name),
+            tiled: args.export_tiled.as_deref().map(name),
+            tiled_tileset: args.export_tiled.as_deref().map(|path| tileset_path(&name(path))),
+            bundle: args.bundle.as_deref().map(name),
+        }
+    }
+
+    // Every file the map may be written as, in bundle order
+    fn files(&self) -> Vec<&str> {
+        [Some(&self.wbox), Some(&self.preview)].into_iter()
+            .chain([&self.legend, &self.used_palette, &self.iso_preview, &self.minimap, &self.quality_map, &self.delta_image, &self.index_image, &self.tiled, &self.tiled_tileset].map(Option::as_ref))
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+
+    // Points every file into `dir` under its own file name, which is also its name in the bundle
+    fn stage_in(&mut self, dir: &Path) -> Result<()> {
+        let mut names = HashSet::new();
+        let files = [&mut self.wbox, &mut self.preview].into_iter().chain(
+            [&mut self.legend, &mut self.used_palette, &mut self.iso_preview, &mut self.minimap, &mut self.quality_map, &mut self.delta_image, &mut self.index_image, &mut self.tiled, &mut self.tiled_tileset]
+                .into_iter()
+                .flat_map(Option::as_mut),
+        );
+        for path in files {
+            let name = file_name(path).to_string();
+            if !names.insert(name.clone()) {
+                anyhow::bail!("Two outputs are named {}, files in a bundle need different names", name);
+            }
+            *path = dir.join(name).to_string_lossy().into_owned();
+        }
+        Ok(())
+    }
+}
+
+// A fresh directory for the files of one bundle, unique across the jobs running at once
+fn staging_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!("herzmapper-bundle-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)))
+}
+
+// Zips the staged files this run wrote into the bundle. Files that are compressed already are stored as they are
+fn write_bundle(bundle: &str, outputs: &Outputs) -> Result<()> {
+    let files: Vec<&str> = outputs.files().into_iter().filter(|path| Path::new(path).is_file()).collect();
+    write_atomic(bundle, |temp| -> Result<()> {
+        let mut zip = ZipWriter::new(BufWriter::new(File::create(temp)?));
+        for path in &files {
+            let compressed = Path::new(path).extension().and_then(|e| e.to_str()).is_some_and(|e| {
+                ["wbox", "png", "jpg", "jpeg", "gif", "webp", "avif"].iter().any(|c| e.eq_ignore_ascii_case(c))
+            });
+            let method = if compressed { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+            zip.start_file(file_name(path), SimpleFileOptions::default().compression_method(method))?;
+            io::copy(&mut File::open(path)?, &mut zip)?;
+        }
+        zip.finish()?.flush()?;
+        Ok(())
+    }).with_context(|| format!("Failed to write bundle: {}", bundle))?;
+    let names: Vec<&str> = files.iter().map(|path| file_name(path)).collect();
+    status!("Bundle written to {}: {}", bundle, names.join(", "));
+    Ok(())
+}
+
+// The preview comes back in the input's format (PNG in, PNG out) unless --preview-image was given.
+// Formats that can't be written, or only at small sizes like ICO, fall back to the PNG default
+fn preview_path(args: &Args, input: &str) -> String {
+    let same_format = ImageFormat::from_path(input).is_ok_and(|f| f.writing_enabled() && f != ImageFormat::Ico);
+    match Path::new(input).extension() {
+        Some(extension) if !args.preview_image_given && same_format => {
+            Path::new(&args.preview_image).with_extension(extension).to_string_lossy().into_owned()
+        }
+        _ => args.preview_image.clone(),
+    }
+}
+
+// Inserts a suffix before the extension (ex: "map.wbox" + "all" -> "map.all.wbox")
+fn suffixed_path(path: &str, suffix: &str) -> String {
+    let p = Path::new(path);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or(path);
+    let name = match p.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}.{}.{}", stem, suffix, ext),
+        None => format!("{}.{}", stem, suffix),
+    };
+    p.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// The Tiled tileset image next to a Tiled map (ex: "map.json" -> "map.tileset.png")
+fn tileset_path(tiled_path: &str) -> String {
+    let p = Path::new(tiled_path);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or(tiled_path);
+    p.with_file_name(format!("{}.tileset.png", stem)).to_string_lossy().into_owned()
+}
+
+// Saves an image atomically, in the format given by the extension of its final path.
+// JPEGs use the given quality, or the encoder's default without one
+fn save_image(img: &RgbImage, path: &str, quality: Option<u8>) -> Result<()> {
+    let format = ImageFormat::from_path(path)?;
+    write_atomic(path, |temp| {
+        match (format, quality) {
+            (ImageFormat::Jpeg, Some(quality)) => {
+                let file = BufWriter::new(File::create(temp)?);
+                img.write_with_encoder(JpegEncoder::new_with_quality(file, quality))?;
+            }
+            _ => img.save_with_format(temp, format)?,
+        }
+        Ok(())
+    })
+}
+
+// Returns the file name of a path (ex: "out/map.wbox" -> "map.wbox")
+fn file_name(path: &str) -> &str {
+    Path::new(path).file_name().and_then(|s| s.to_str()).unwrap_or(path)
+}
+
+// Reads the map_data template from a file or a URL
+fn read_template(path: &str) -> Result<String> {
+    if remote::is_url(path) {
+        return String::from_utf8(remote::fetch(path)?).with_context(|| format!("Map data {} is not UTF-8 text", path));
+    }
+    fs::read_to_string(path).with_context(|| format!("Failed to read map data file: {}", path))
+}
+
+// Looks for a relative resource path (palette, map data, world laws) missing from the current directory
+// in the executable's directory, then in the first input's directory. None when it needs no fallback
+// or neither has it, so the usual error names the path as given
+fn resolve_resource(path: &str, input: Option<&str>) -> Option<String> {
+    let relative = Path::new(path);
+    if remote::is_url(path) || relative.is_absolute() || relative.exists() {
+        return None;
+    }
+    let exe_dir = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf));
+    let input_dir = input.filter(|input| !remote::is_url(input)).and_then(|input| Path::new(input).parent()).map(Path::to_path_buf);
+    exe_dir.into_iter().chain(input_dir)
+        .map(|dir| dir.join(relative))
+        .find(|candidate| candidate.exists())
+        .map(|found| found.to_string_lossy().into_owned())
+}
+
+// Returns the file name of a path without its extension (ex: "palettes/all.txt" -> "all")
+fn file_stem(path: &str) -> &str {
+    Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or(path)
+}
+
+// Rebuilds a command line that replays this run: every argument with its effective value, defaults included.
+// Defaults of arguments that conflict with one given explicitly are left out, since the replay would reject them
+fn effective_command(matches: &ArgMatches) -> String {
+    let command = Args::command();
+    let given = |id: &str| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+    let conflicts = |arg: &clap::Arg| command.get_arguments().any(|other| {
+        given(other.get_id().as_str()) && (command.get_arg_conflicts_with(arg).contains(&other)
+            || command.get_arg_conflicts_with(other).contains(&arg))
+    });
+    let mut words = vec!["herzmapper".to_string()];
+    let mut positionals = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            if let Some(values) = matches.get_raw(id) {
+                positionals.extend(values.map(|v| v.to_string_lossy().into_owned()));
+            }
+            continue;
+        };
+        // The default preview path follows each input's format, so writing it out would pin it to PNG
+        let input_dependent = long == "preview-image" && !given(id);
+        if long == "log-command" || long == "help" || long == "version" || input_dependent || (!given(id) && conflicts(arg)) {
+            continue;
+        }
+        let Some(occurrences) = matches.get_raw_occurrences(id) else { continue };
+        for occurrence in occurrences {
+            let values: Vec<String> = occurrence.map(|v| v.to_string_lossy().into_owned()).collect();
+            match arg.get_action() {
+                // Flags are written only when they differ from their unset state
+                ArgAction::SetTrue if values == ["true"] => words.push(format!("--{}", long)),
+                ArgAction::SetFalse if values == ["false"] => words.push(format!("--{}", long)),
+                ArgAction::SetTrue | ArgAction::SetFalse => {}
+                _ => {
+                    words.push(format!("--{}", long));
+                    match arg.get_value_delimiter() {
+                        Some(delimiter) => words.push(values.join(&delimiter.to_string())),
+                        None => words.extend(values),
+                    }
+                }
+            }
+        }
+    }
+    words.extend(positionals);
+    words.iter().map(|word| shell_quote(word)).collect::<Vec<_>>().join(" ")
+}
+
+// Formats a time as an RFC 3339 UTC timestamp to the second (ex: "2025-01-31T12:00:00Z")
+fn utc_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = (seconds / 86400, seconds % 86400);
+    // Civil date from days since 1970-01-01, in 400-year eras starting on March 1st so leap days come last
+    let shifted = days + 719_468;
+    let (era, day_of_era) = (shifted / 146_097, shifted % 146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+// Quotes a word for POSIX shells when it holds anything beyond plain path and value characters
+fn shell_quote(word: &str) -> String {
+    if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c)) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.preview_image_given = matches.value_source("preview_image") == Some(clap::parser::ValueSource::CommandLine);
+    if args.log_command.is_some() || args.embed_command {
+        args.command_line = effective_command(&matches);
+    }
+    STATUS_TO_STDERR.store(args.output_base64, Ordering::Relaxed);
+    let _ = BUDGET.set(Budget::new(args.max_time, args.max_output_bytes));
+    // Dragging an image onto the executable starts it in another directory than the bundled resources
+    let first_input = args.input.first().cloned();
+    for path in args.palette.iter_mut().chain([&mut args.map_data, &mut args.world_laws, &mut args.special_palette]) {
+        if let Some(found) = resolve_resource(path, first_input.as_deref()) {
+            status!("{} not found in the current directory, using {}", path, found);
+            *path = found;
+        }
+    }
+    // An inline palette stands in for the single default palette file
+    if args.palette_inline.is_some() {
+        args.palette = vec![INLINE_PALETTE.to_string()];
+    }
+    // So does a tiered palette, which `convert` matches by brightness
+    if let Some(tiered) = &args.tiered_palette {
+        args.palette = vec![tiered.clone()];
+    }
+    if let Some(command) = &args.command {
+        if let Err(e) = run_command(command) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {:?}", e);
+    }
+
+    if args.no_pause {
+        status!("Press Enter to exit...");
+        let mut input = String::new();
+        stdin().read_line(&mut input).unwrap();
+    }
+}
+
+
+fn run_command(command: &Command) -> Result<()> {
+    match command {
+        Command::Validate { file } => {
+            let violations = wbox::validate(&read_wbox(file)?);
+            if violations.is_empty() {
+                status!("{} is valid", file);
+                return Ok(());
+            }
+            for violation in &violations {
+                status!("- {}", violation);
+            }
+            anyhow::bail!("{} has {} problem(s)", file, violations.len())
+        }
+        Command::CompilePalette { palette, output, csv_columns } => {
+            let start = Instant::now();
+            let parsed = load_palette_file(palette, csv_columns, false)?;
+            parsed.save_compiled(output).with_context(|| format!("Failed to write compiled palette: {}", output))?;
+            status!("Compiled {} entries of {} to {} in {:?}", parsed.len(), palette, output, start.elapsed());
+            Ok(())
+        }
+        Command::Info { file, entries } => {
+            let map_data = read_wbox(file)?;
+            let bands = map_data["chunks"].as_array().map(Vec::len);
+            let map_data = wbox::join_chunks(&map_data)?;
+            let count = |value: &Value| value.as_array().map_or(0, Vec::len);
+            let (width, height) = (map_data["width"].as_u64().unwrap_or(0), map_data["height"].as_u64().unwrap_or(0));
+            let amounts = map_data["tileAmounts"].as_array().map(|rows| rows.iter().map(|row| {
+                row.as_array().map_or((0, 0), |row| (row.len(), row.iter().filter_map(Value::as_u64).sum::<u64>()))
+            }).fold((0, 0), |(runs, tiles), (r, t)| (runs + r, tiles + t))).unwrap_or((0, 0));
+            let laws = map_data["worldLaws"]["list"].as_array().map(Vec::as_slice).unwrap_or_default();
+            let disabled = laws.iter().filter(|law| law["boolVal"] == json!(false)).count();
+            let tile_map: Vec<&str> = map_data["tileMap"].as_array().map(Vec::as_slice).unwrap_or_default()
+                .iter().filter_map(Value::as_str).collect();
+
+            status!("{}", file);
+            status!("  Size:          {}x{} cells ({}x{} tiles)", width, height, width * 64, height * 64);
+            status!("  Tiles:         {} in {} runs", amounts.1, amounts.0);
+            if let Some(bands) = bands {
+                status!("  Chunks:        {} bands", bands);
+            }
+            status!("  World laws:    {} ({} disabled)", laws.len(), disabled);
+            status!("  Frozen tiles:  {}", count(&map_data["frozen_tiles"]));
+            status!("  Objects:       {}", count(&map_data["mapObjects"]));
+            let provenance = &map_data["_herzmapper"];
+            if let (Some(tool), Some(version)) = (provenance["tool"].as_str(), provenance["version"].as_str()) {
+                status!("  Made by:       {} {} at {}", tool, version, provenance["timestamp"].as_str().unwrap_or("an unknown time"));
+            }
+            if let Some(command) = provenance["command"].as_str() {
+                status!("  Command:       {}", command);
+            }
+            status!("  tileMap:       {} entries", tile_map.len());
+            for id in tile_map.iter().take(*entries) {
+                status!("    {}", id);
+            }
+            if tile_map.len() > *entries {
+                status!("    ... and {} more", tile_map.len() - entries);
+            }
+            Ok(())
+        }
+        Command::Selftest { size } => selftest(*size),
+        Command::Diff { old, new, output } => {
+            let (width, height, old_tiles) = decode_tiles(&read_wbox(old)?, old)?;
+            let (new_width, new_height, new_tiles) = decode_tiles(&read_wbox(new)?, new)?;
+            if (width, height) != (new_width, new_height) {
+                anyhow::bail!("{} is {}x{} tiles but {} is {}x{}, only maps of the same size can be compared", old, width, height, new, new_width, new_height);
+            }
+
+            // Changed tiles are red, unchanged ones dark grey
+            let mut changes: HashMap<(&str, &str), usize> = HashMap::new();
+            let image = RgbImage::from_fn(width, height, |x, y| {
+                let i = (y * width + x) as usize;
+                if old_tiles[i] == new_tiles[i] {
+                    Rgb([48, 48, 48])
+                } else {
+                    *changes.entry((old_tiles[i].as_str(), new_tiles[i].as_str())).or_default() += 1;
+                    Rgb([255, 0, 0])
+                }
+            });
+            save_image(&image, output, None).with_context(|| format!("Failed to save diff image: {}", output))?;
+
+            let changed: usize = changes.values().sum();
+            status!("{} of {} tiles changed ({:.2}%)", changed, old_tiles.len(), changed as f64 * 100.0 / old_tiles.len() as f64);
+            let mut changes: Vec<((&str, &str), usize)> = changes.into_iter().collect();
+            changes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            for ((from, to), count) in changes {
+                status!("  {:<32} -> {:<32} {:>10}", from, to, count);
+            }
+            status!("Diff image written to {}", output);
+            Ok(())
+        }
+    }
+}
+
+// Runs the whole conversion on generated files in a temporary directory: a 64-color palette and an
+// image of its colors, each pixel nudged off its color so the nearest-color search is exercised.
+// The output must validate and decode back to exactly the tiles the image was drawn with
+fn selftest(size: u32) -> Result<()> {
+    let start = Instant::now();
+    let dir = std::env::temp_dir().join(format!("herzmapper-selftest-{}", std::process::id()));
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let result = (|| -> Result<()> {
+        // 4 levels per channel, 85 apart, so a nudge of up to 3 still has the same nearest color
+        let colors: Vec<[u8; 3]> = (0..64u8).map(|i| [i / 16 * 85, i / 4 % 4 * 85, i % 4 * 85]).collect();
+        let palette: String = colors.iter().enumerate().map(|(i, [r, g, b])| format!("selftest_{:02} #{:02X}{:02X}{:02X}\n", i, r, g, b)).collect();
+        let index = |x: u32, y: u32| ((x / 16 + y / 16 * 7) % 64) as usize;
+        let img = RgbImage::from_fn(size, size, |x, y| {
+            let nudge = |c: u8, k: u32| if c == 255 { c - (k % 4) as u8 } else { c + (k % 4) as u8 };
+            let [r, g, b] = colors[index(x, y)];
+            Rgb([nudge(r, x), nudge(g, y), nudge(b, x + y)])
+        });
+        fs::write(path("palette.txt"), palette)?;
+        fs::write(path("map_data.json"), r#"{"tileMap": [], "worldLaws": {"list": []}}"#)?;
+        fs::write(path("laws.txt"), "")?;
+        img.save(path("input.png"))?;
+        status!("Generated a {}x{} image and a {} color palette in {:?}", size, size, colors.len(), start.elapsed());
+
+        let args = Args::parse_from([
+            "herzmapper", &path("input.png"), "-p", &path("palette.txt"), "-m", &path("map_data.json"),
+            "-w", &path("laws.txt"), "-o", &path("map.wbox"), "--preview-image", &path("preview.png"), "-n",
+        ]);
+        let convert_start = Instant::now();
+        convert_input(&Job { input: path("input.png"), suffix: None, own_output: false, args })?;
+        let elapsed = convert_start.elapsed();
+
+        let map_data = read_wbox(&path("map.wbox"))?;
+        let violations = wbox::validate(&map_data);
+        if !violations.is_empty() {
+            anyhow::bail!("The generated map is invalid:\n- {}", violations.join("\n- "));
+        }
+        let (_, _, tiles) = decode_tiles(&map_data, "The generated map")?;
+        let wrong = tiles.iter().enumerate()
+            .filter(|&(i, id)| *id != format!("selftest_{:02}", index(i as u32 % size, i as u32 / size)))
+            .count();
+        if wrong > 0 {
+            anyhow::bail!("{} of {} tiles didn't decode to the color they were drawn with", wrong, tiles.len());
+        }
+        status!(
+            "Selftest passed: {} tiles converted in {:?} ({:.1} million tiles per second)",
+            tiles.len(), elapsed, tiles.len() as f64 / elapsed.as_secs_f64() / 1e6
+        );
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn run(args: &Args) -> Result<()> {
+    let start = Instant::now();
+
+    // Fail before any work rather than writing a map that silently applies the laws everywhere
+    if let Some(region_laws) = &args.region_laws {
+        anyhow::bail!(
+            "--region-laws {} {} can't be applied: .wbox files only hold one worldLaws list for the whole map, with no field for laws \
+            scoped to a region. Use --world-laws for map-wide laws",
+            region_laws[0], region_laws[1]
+        );
+    }
+
+    if args.container != Container::Zlib {
+        let name = args.container.to_possible_value().expect("containers are never skipped");
+        eprintln!("Warning: --container {} writes maps the game can't load, only keep them as archives", name.get_name());
+    }
+
+    if let Some(log_path) = &args.log_command {
+        write_atomic(log_path, |temp| -> Result<()> { Ok(fs::write(temp, format!("{}\n", args.command_line))?) })
+            .with_context(|| format!("Failed to write command log: {}", log_path))?;
+        status!("Command line written to {}", log_path);
+    }
+
+    // A LUT can be built on its own, without any input to convert
+    if let Some(lut_path) = &args.build_lut {
+        build_luts(args, lut_path)?;
+        if args.input.is_empty() && args.manifest.is_none() && args.layers_dir.is_none() && args.generate.is_none() {
+            return Ok(());
+        }
+    }
+
+    // The channels of a data image stand in for the layer files of every job
+    let data_args;
+    let args = match &args.data_image {
+        Some(data_image) => {
+            data_args = data_image_args(args, data_image)?;
+            &data_args
+        }
+        None => args,
+    };
+
+    // Every input becomes a job, either straight from the command line or from a manifest
+    let jobs = if let Some(manifest_path) = &args.manifest {
+        let jobs = manifest::load(manifest_path, args)?;
+        status!("Manifest {} lists {} job(s)", manifest_path, jobs.len());
+        jobs
+    } else if let Some(layers_dir) = &args.layers_dir {
+        vec![layers_job(args, layers_dir)?]
+    } else if args.generate.is_some() {
+        vec![Job { args: args.clone(), input: GENERATED_INPUT.to_string(), suffix: None, own_output: false }]
+    } else {
+        // Ensure we have a valid input path
+        if args.input.is_empty() {
+            anyhow::bail!("No input file provided");
+        }
+        let batch = args.input.len() > 1;
+        args.input.iter().map(|input_path| Job {
+            args: args.clone(),
+            input: input_path.clone(),
+            suffix: batch.then(|| file_stem(input_path).to_string()),
+            own_output: false,
+        }).collect()
+    };
+
+    // Up to batch_concurrency jobs are converted at once. Each worker gets its own rayon pool
+    // with a share of the cores, so concurrent images don't oversubscribe the CPU
+    let workers = args.batch_concurrency.clamp(1, jobs.len());
+    let results: Vec<Mutex<Option<Result<Vec<Thumbnail>>>>> = jobs.iter().map(|_| Mutex::new(None)).collect();
+    if workers == 1 {
+        for (i, job) in jobs.iter().enumerate() {
+            *results[i].lock().unwrap() = Some(convert_input(job));
+        }
+    } else {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let threads_per_worker = (cores / workers).max(1);
+        status!("Converting {} inputs, {} at a time with {} threads each", jobs.len(), workers, threads_per_worker);
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| -> Result<()> {
+            for _ in 0..workers {
+                let pool = rayon::ThreadPoolBuilder::new().num_threads(threads_per_worker).build()?;
+                let (next, results, jobs) = (&next, &results, &jobs);
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(i) else { break };
+                    *results[i].lock().unwrap() = Some(pool.install(|| convert_input(job)));
+                });
+            }
+            Ok(())
+        })?;
+    }
+
+    // Results stay in job order, so the contact sheet is laid out the same however the jobs ran
+    let mut thumbnails = Vec::new();
+    let mut failed = 0;
+    for (job, result) in jobs.iter().zip(results) {
+        match result.into_inner().unwrap().expect("Every job is converted") {
+            Ok(thumbs) => thumbnails.extend(thumbs),
+            Err(e) if jobs.len() == 1 => return Err(e),
+            Err(e) => {
+                eprintln!("Error converting {}: {:?}", job.input, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if let Some(sheet_path) = &args.contact_sheet {
+        save_image(&sheet::contact_sheet(&thumbnails, args.contact_columns), sheet_path, args.preview_quality)
+            .with_context(|| format!("Failed to save contact sheet: {}", sheet_path))?;
+        status!("Contact sheet written to {}", sheet_path);
+    }
+
+    let elapsed = start.elapsed();
+    if jobs.len() > 1 {
+        status!(
+            "Converted {} of {} inputs in {:?} ({:?} per input on average)",
+            jobs.len() - failed, jobs.len(), elapsed, elapsed / jobs.len() as u32
+        );
+    }
+    status!("Total execution time: {:?}", elapsed);
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} inputs failed to convert", failed, jobs.len());
+    }
+    Ok(())
+}
+
+// Builds the job for a directory of layer images exported from an image editor. terrain.png is the input,
+// every other layer found fills its stage unless the matching flag was given. Missing layers are skipped
+fn layers_job(args: &Args, dir: &str) -> Result<Job> {
+    let layer = |name: &str| {
+        let path = Path::new(dir).join(format!("{}.png", name));
+        path.is_file().then(|| path.to_string_lossy().into_owned())
+    };
+    let input = layer("terrain").with_context(|| format!("Layers directory {} has no terrain.png", dir))?;
+    let mut args = args.clone();
+    let stages = [
+        ("river", &mut args.river_map),
+        ("water", &mut args.water_mask),
+        ("depth", &mut args.depth_map),
+        ("objects", &mut args.objects),
+    ];
+    for (name, stage) in stages {
+        if stage.is_none() && let Some(path) = layer(name) {
+            status!("Using layer {}", path);
+            *stage = Some(path);
+        }
+    }
+    for (name, stage) in [("freeze", &mut args.freeze_map), ("height", &mut args.height_map)] {
+        if stage.is_empty() && let Some(path) = layer(name) {
+            status!("Using layer {}", path);
+            stage.push(path);
+        }
+    }
+    if args.depth_map.is_some() && !args.height_map.is_empty() {
+        status!("Both depth.png and height.png found, using depth.png");
+        args.height_map.clear();
+    }
+    if layer("temperature").is_some() {
+        status!("Skipping temperature.png, maps have no temperature data to write it to");
+    }
+    Ok(Job { args, input, suffix: None, own_output: false })
+}
+
+// Channels of a --data-image, by their letter in --data-channels
+const DATA_CHANNELS: [&str; 4] = ["r", "g", "b", "a"];
+
+// Points the layers of --data-channels at channels of the --data-image. A channel stands in for a layer
+// image as "IMAGE#r" (see `data_channel`), and a layer flag given on the command line wins over it
+fn data_image_args(args: &Args, data_image: &str) -> Result<Args> {
+    let mut channels: HashMap<&str, Vec<String>> = HashMap::new();
+    for assignment in &args.data_channels {
+        let (channel, layer) = assignment.split_once('=')
+            .with_context(|| format!("Invalid --data-channels entry {}, expected CHANNEL=LAYER (ex: r=freeze)", assignment))?;
+        let channel = channel.trim().to_ascii_lowercase();
+        if !DATA_CHANNELS.contains(&channel.as_str()) {
+            anyhow::bail!("Invalid --data-channels channel {}, expected one of {}", channel, DATA_CHANNELS.join(", "));
+        }
+        match layer.trim() {
+            layer @ ("freeze" | "height" | "depth" | "river" | "water") => {
+                channels.entry(layer).or_default().push(format!("{}#{}", data_image, channel));
+            }
+            "temperature" => status!("Skipping the {} channel of {}, maps have no temperature data to write it to", channel, data_image),
+            "none" => {}
+            other => anyhow::bail!(
+                "Invalid --data-channels layer {}, expected freeze, height, depth, river, water, temperature or none", other
+            ),
+        }
+    }
+
+    let mut args = args.clone();
+    for (name, stage) in [("river", &mut args.river_map), ("water", &mut args.water_mask), ("depth", &mut args.depth_map)] {
+        match channels.get(name).map(Vec::as_slice) {
+            Some([path]) if stage.is_none() => {
+                status!("Using channel {} as the {} layer", path, name);
+                *stage = Some(path.clone());
+            }
+            Some([path]) => status!("The {} layer is given on the command line, channel {} is not used", name, path),
+            Some(_) => anyhow::bail!("--data-channels assigns several channels to the {} layer, which takes one image", name),
+            None => {}
+        }
+    }
+    // Several freeze or height channels combine like several --freeze-map or --height-map images
+    for (name, stage) in [("freeze", &mut args.freeze_map), ("height", &mut args.height_map)] {
+        match channels.get(name) {
+            Some(paths) if stage.is_empty() => {
+                status!("Using channel(s) {} as the {} layer", paths.join(", "), name);
+                stage.extend(paths.iter().cloned());
+            }
+            Some(paths) => status!("The {} layer is given on the command line, channel(s) {} not used", name, paths.join(", ")),
+            None => {}
+        }
+    }
+    Ok(args)
+}
+
+// The --data-image and channel an auxiliary path stands for, None for an ordinary file
+fn data_channel<'a>(args: &'a Args, path: &str) -> Option<(&'a str, usize)> {
+    let data_image = args.data_image.as_deref()?;
+    let channel = path.strip_prefix(data_image)?.strip_prefix('#')?;
+    Some((data_image, DATA_CHANNELS.iter().position(|c| *c == channel)?))
+}
+
+// One channel of an image as a gray image, so every layer reads it like its own layer file
+fn channel_image(path: &str, channel: usize) -> Result<RgbImage> {
+    let img = open(path).with_context(|| format!("Failed to open data image: {}", path))?.into_rgba8();
+    Ok(RgbImage::from_fn(img.width(), img.height(), |x, y| Rgb([img.get_pixel(x, y)[channel]; 3])))
+}
+
+// A labelled contact sheet thumbnail
+type Thumbnail = (String, RgbImage);
+
+// Converts one job's input with every palette. With several inputs the outputs are named after each input,
+// with several palettes after each palette as well (ex: map.example.all.wbox)
+fn convert_input(job: &Job) -> Result<Vec<Thumbnail>> {
+    let start = Instant::now();
+    let args = &job.args;
+    let decoded = match args.generate {
+        Some((width, height)) => {
+            let world = generate_world(args, width, height)?;
+            status!("World of {}x{} tiles generated from seed {} in {:?}", width, height, args.seed, start.elapsed());
+            DynamicImage::ImageRgb8(world)
+        }
+        None => {
+            let decoded = load_input(&job.input)?;
+            status!("Image {} loaded in {:?}", job.input, start.elapsed());
+            decoded
+        }
+    };
+    let blend = match args.blend.as_slice() {
+        [path, factor] => {
+            let factor: f64 = factor.parse().ok().filter(|f| (0.0..=1.0).contains(f))
+                .with_context(|| format!("--blend factor {} must be a number from 0.0 to 1.0", factor))?;
+            Some((path, load_input(path)?, factor))
+        }
+        _ => None,
+    };
+    check_budget("loading the input")?;
+
+    // Every scale is resized from the same decoded image and gets its own outputs
+    let scales: Vec<Option<u32>> = if args.scales.is_empty() { vec![None] } else { args.scales.iter().copied().map(Some).collect() };
+    let mut thumbnails = Vec::new();
+    // The automatic palette is picked once per input, from its first scale
+    let mut selected_palette: Option<Vec<String>> = None;
+    for scale in scales {
+        let mut source = prepare_source(args, &decoded, scale)?;
+        if let Some((path, other, factor)) = &blend {
+            blend_source(args, &mut source, other, *factor, scale)?;
+            status!("Blended with {} at {}", path, factor);
+        }
+        if let Some((hue, saturation, brightness)) = args.hsb {
+            let before = unique_colors(&source.img).len();
+            adjust_source_hsb(&mut source.img, hue, saturation / 100.0, brightness / 100.0);
+            let after = unique_colors(&source.img).len();
+            status!(
+                "Adjusted hue {:+}°, saturation {:+}, brightness {:+}: {} unique colors before, {} after ({:+.1}%)",
+                hue, saturation, brightness, before, after, (after as f64 - before as f64) * 100.0 / before as f64
+            );
+        }
+        if let Some(radius) = args.denoise {
+            let denoise_start = Instant::now();
+            let before = unique_colors(&source.img).len();
+            source.img = denoise::median(&source.img, radius);
+            let after = unique_colors(&source.img).len();
+            status!(
+                "Denoised with radius {} in {:?}: {} unique colors before, {} after ({:+.1}%)",
+                radius, denoise_start.elapsed(), before, after, (after as f64 - before as f64) * 100.0 / before as f64
+            );
+        }
+        check_budget("preparing the image")?;
+        check_uniform(args, &job.input, &source.img)?;
+        let scale_suffix = scale.map(|_| format!("{}x{}", source.img.width(), source.img.height()));
+        if let Some(suffix) = &scale_suffix {
+            status!("Scaled to {} tiles", suffix);
+        }
+        if let Some(dir) = &args.auto_select_palette
+            && selected_palette.is_none()
+        {
+            selected_palette = Some(vec![select_palette(args, dir, &source.img)?]);
+        }
+        let palettes = selected_palette.as_deref().unwrap_or(&args.palette);
+        for palette_path in palettes {
+            let palette_suffix = (palettes.len() > 1).then(|| file_stem(palette_path));
+            let own_suffixes: Vec<&str> = scale_suffix.as_deref().into_iter().chain(palette_suffix).collect();
+            let suffixes: Vec<&str> = job.suffix.as_deref().into_iter().chain(own_suffixes.iter().copied()).collect();
+            let mut outputs = Outputs::new(args, &job.input, &suffixes);
+            if job.own_output {
+                outputs.wbox = own_suffixes.iter().fold(args.output.clone(), |path, suffix| suffixed_path(&path, suffix));
+            }
+            // A bundle's files are written to a staging directory of their own, zipped, then removed
+            let preview = match outputs.bundle.clone() {
+                Some(bundle) => {
+                    let dir = staging_dir();
+                    outputs.stage_in(&dir)?;
+                    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+                    let result = convert(args, &source, palette_path, &outputs)
+                        .and_then(|preview| write_bundle(&bundle, &outputs).map(|()| preview));
+                    let _ = fs::remove_dir_all(&dir);
+                    result?
+                }
+                None => convert(args, &source, palette_path, &outputs)?,
+            };
+            if args.contact_sheet.is_some() {
+                let label = file_name(&outputs.wbox).to_string();
+                thumbnails.push((label, sheet::thumbnail(&preview, 256)));
+            }
+        }
+    }
+    Ok(thumbnails)
+}
+
+// Ranks every palette of a directory by the total distance from the image's unique colors to their
+// nearest palette color and returns the best one. Files that aren't palettes are skipped
+fn select_palette(args: &Args, dir: &str, img: &RgbImage) -> Result<String> {
+    let start = Instant::now();
+    let mut paths: Vec<String> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read palette directory: {}", dir))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    paths.sort();
+    let unique = unique_colors(img);
+    let options = quantize_options(args);
+    let mut ranking = Vec::new();
+    for path in paths {
+        match Palette::load(&path).and_then(|palette| match_distance(&unique, &palette, &options)) {
+            Ok(distance) => ranking.push((distance, path)),
+            Err(e) => eprintln!("Skipping {}: {}", path, e),
+        }
+    }
+    ranking.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    let Some((_, best)) = ranking.first() else {
+        anyhow::bail!("{} holds no usable palette", dir);
+    };
+    status!("Palettes ranked by mean distance to the {} colors of the input in {:?}:", unique.len(), start.elapsed());
+    for (i, (distance, path)) in ranking.iter().enumerate() {
+        status!("  {:>2}. {:<40} {:>8.2}", i + 1, file_name(path), distance / unique.len() as f64);
+    }
+    status!("Using palette {}", best);
+    Ok(best.clone())
+}
+
+// Warns (or fails with --strict) when an input is a single color or almost entirely one color,
+// which usually means the wrong file or layer was exported
+fn check_uniform(args: &Args, input_path: &str, img: &RgbImage) -> Result<()> {
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    for p in img.pixels() {
+        *counts.entry(p.0).or_default() += 1;
+    }
+    let (&color, &most) = counts.iter().max_by_key(|&(_, &count)| count).unwrap();
+    let share = most as f64 / img.pixels().len() as f64;
+    let problem = if counts.len() == 1 {
+        format!("{} is a single color (#{:02X}{:02X}{:02X}), the map will be one tile", input_path, color[0], color[1], color[2])
+    } else if share >= UNIFORM_SHARE {
+        format!(
+            "{} is {:.2}% one color (#{:02X}{:02X}{:02X}), the map will be almost uniform",
+            input_path, share * 100.0, color[0], color[1], color[2]
+        )
+    } else {
+        return Ok(());
+    };
+    if args.strict {
+        anyhow::bail!("Degenerate input, {}", problem);
+    }
+    eprintln!("Warning: {}", problem);
+    Ok(())
+}
+
+// Paints a --generate world in the colors of the first palette: every tile takes the first --biomes id
+// whose level its noise height doesn't exceed. The image then goes through the usual conversion
+fn generate_world(args: &Args, width: u32, height: u32) -> Result<RgbImage> {
+    if !(args.noise_scale.is_finite() && args.noise_scale > 0.0) {
+        anyhow::bail!("--noise-scale {} must be a positive number of tiles", args.noise_scale);
+    }
+    let palette = load_palette(args, &args.palette[0])?;
+    let mut biomes: Vec<(usize, f64)> = if args.biomes.is_empty() {
+        (0..palette.len()).map(|i| (i, (i + 1) as f64 / palette.len() as f64)).collect()
+    } else {
+        args.biomes.iter().map(|biome| {
+            let (id, level) = biome.rsplit_once(':').with_context(|| format!("Invalid --biomes entry {}, expected ID:LEVEL", biome))?;
+            let level: f64 = level.trim().parse().ok().filter(|l| (0.0..=1.0).contains(l))
+                .with_context(|| format!("--biomes level {} of {} must be a number from 0 to 1", level, id))?;
+            let index = palette.ids.iter().position(|p| p == id.trim())
+                .with_context(|| format!("--biomes id {} is not in palette {}", id, args.palette[0]))?;
+            Ok((index, level))
+        }).collect::<Result<_>>()?
+    };
+    biomes.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let heights = noise::heightmap(width, height, args.seed, args.noise_scale);
+    let highest = biomes[biomes.len() - 1].0;
+    Ok(RgbImage::from_fn(width, height, |x, y| {
+        let h = heights[(y * width + x) as usize];
+        let biome = biomes.iter().find(|(_, level)| h <= *level).map_or(highest, |(index, _)| *index);
+        Rgb(palette.color(biome).into())
+    }))
+}
+
+// Decodes an input image once; every scale and palette works from the same decoded image
+fn load_input(input_path: &str) -> Result<DynamicImage> {
+    let decoded = if remote::is_url(input_path) {
+        let data = remote::fetch(input_path)?;
+        status!("Downloaded {} ({} KB)", input_path, data.len() / 1024);
+        ImageReader::new(Cursor::new(data)).with_guessed_format()?.decode()?
+    } else {
+        ImageReader::open(input_path)
+            .with_context(|| format!("Failed to open input image: {}", input_path))?
+            .with_guessed_format()?
+            .decode()?
+    };
+    let color = decoded.color();
+    let bits = color.bytes_per_pixel() as u16 * 8 / color.channel_count() as u16;
+    if bits > 8 {
+        // The palette only holds 8-bit colors, so each channel is rounded to the nearest 8-bit value
+        status!("{} has {} bits per channel, rounding to 8 bits for matching", input_path, bits);
+    }
+    Ok(decoded)
+}
+
+// Resizes an image to a map `scale` tiles wide, keeping the aspect ratio with whole cells
+fn scale_image<P: Pixel + 'static>(img: ImageBuffer<P, Vec<P::Subpixel>>, scale: Option<u32>) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let Some(width) = scale else {
+        return img;
+    };
+    let height = ((img.height() as f64 * width as f64 / img.width() as f64 / 64.0).round() as u32).max(2) * 64;
+    resize(&img, width, height, image::imageops::FilterType::Nearest)
+}
+
+// Stretches an image to exactly the --force-tiles grid, warning when its aspect ratio changes by more than 1%
+fn force_size<P: Pixel + 'static>(img: ImageBuffer<P, Vec<P::Subpixel>>, args: &Args) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let Some((columns, rows)) = args.force_tiles else {
+        return img;
+    };
+    let (width, height) = (columns * 64, rows * 64);
+    let (from, to) = (img.width() as f64 / img.height() as f64, width as f64 / height as f64);
+    let distortion = (to / from - 1.0).abs();
+    if distortion > 0.01 {
+        eprintln!(
+            "Warning: --force-tiles {}x{} stretches the {}x{} input (aspect {:.2}) to {}x{} tiles (aspect {:.2}), distorting it by {:.0}%",
+            columns, rows, img.width(), img.height(), from, width, height, to, distortion * 100.0
+        );
+    }
+    if img.dimensions() == (width, height) {
+        return img;
+    }
+    resize(&img, width, height, imageops::FilterType::Nearest)
+}
+
+// Tiles, flips and resizes a decoded input to the tile grid, or to `scale` tiles wide when given.
+// The alpha channel is kept so transparent pixels can become the background tile
+fn prepare_source(args: &Args, decoded: &DynamicImage, scale: Option<u32>) -> Result<Source> {
+    // Padding is marked transparent, so it becomes the background tile like transparent pixels
+    let keep_alpha = decoded.color().has_alpha() && (args.background_id.is_some() || !args.shore_tiles.is_empty());
+    let source = if keep_alpha || args.snap == Snap::Pad {
+        let mut rgba = prepare_image(decoded.to_rgba8(), args)?;
+        if !keep_alpha {
+            rgba.pixels_mut().for_each(|p| p[3] = 255);
+        }
+        let original_size = rgba.dimensions();
+        let rgba = snap_image(scale_image(force_size(rgba, args), scale), args.snap, image::Rgba([0, 0, 0, 0]))?;
+        let alpha: Vec<u8> = rgba.pixels().map(|p| p[3]).collect();
+        let img = DynamicImage::ImageRgba8(rgba).into_rgb8();
+        Source { img, alpha: Some(alpha), original_size }
+    } else {
+        let img = prepare_image(decoded.to_rgb8(), args)?;
+        let original_size = img.dimensions();
+        Source { img: snap_image(scale_image(force_size(img, args), scale), args.snap, Rgb([0, 0, 0]))?, alpha: None, original_size }
+    };
+    if let Some((rows, columns)) = args.tile_source {
+        let (w, h) = source.original_size;
+        status!("Source tiled {}x{} to {}x{}, {}x{} tiles after resizing", rows, columns, w, h, source.img.width(), source.img.height());
+    }
+    Ok(source)
+}
+
+// Applies the --hsb adjustment to every pixel. Pixels of one color are adjusted alike, so it's done once per color
+fn adjust_source_hsb(img: &mut RgbImage, hue: f64, saturation: f64, brightness: f64) {
+    let mut adjusted: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
+    for pixel in img.pixels_mut() {
+        pixel.0 = *adjusted.entry(pixel.0).or_insert_with(|| adjust_hsv(pixel.0, hue, saturation, brightness));
+    }
+}
+
+// Mixes a second image into the source in linear light, tiled, flipped and resized like the input.
+// Its transparency is ignored, the input's alpha still decides the background tiles
+fn blend_source(args: &Args, source: &mut Source, other: &DynamicImage, factor: f64, scale: Option<u32>) -> Result<()> {
+    let mut other = snap_image(scale_image(force_size(prepare_image(other.to_rgb8(), args)?, args), scale), args.snap, Rgb([0, 0, 0]))?;
+    let (width, height) = source.img.dimensions();
+    if other.dimensions() != (width, height) {
+        other = resize(&other, width, height, imageops::FilterType::Nearest);
+    }
+    for (pixel, mixed) in source.img.pixels_mut().zip(other.pixels()) {
+        pixel.0 = blend_linear(pixel.0, mixed.0, factor);
+    }
+    Ok(())
+}
+
+// Applies --snap-policy to the color matches: exact palette colors are always kept, other colors keep
+// their match only with the nearest policy, or when within --max-distance (CIELAB ΔE) with the limited one.
+// Rejected pixels take the background tile, also in the preview. Returns how many pixels were rejected
+fn reject_matches(args: &Args, img: &RgbImage, palette: &Palette, grid: &mut [usize], tile_ids: &mut Vec<String>, preview: &mut RgbImage) -> usize {
+    if args.snap_policy == SnapPolicy::Nearest {
+        return 0;
+    }
+    let lab = |(r, g, b): (u8, u8, u8)| ColorSpace::Lab.convert([r as f64, g as f64, b as f64]);
+    let mut accepted: HashMap<((u8, u8, u8), usize), bool> = HashMap::new();
+    let mut rejected = Vec::new();
+    for (i, p) in img.pixels().enumerate() {
+        let color = (p[0], p[1], p[2]);
+        let keep = *accepted.entry((color, grid[i])).or_insert_with(|| {
+            palette.exact_index(color).is_some() || (args.snap_policy == SnapPolicy::Limited && {
+                let (a, b) = (lab(color), lab(palette.color(grid[i])));
+                a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum::<f64>().sqrt() <= args.max_distance
+            })
+        });
+        if !keep {
+            rejected.push(i);
+        }
+    }
+    if !rejected.is_empty() {
+        let background = tile_index(tile_ids, args.background_id.as_deref().unwrap_or(DEFAULT_BACKGROUND_ID));
+        let color = (background < palette.len()).then(|| Rgb(palette.color(background).into()));
+        for &i in &rejected {
+            grid[i] = background;
+            if let Some(color) = color {
+                preview.put_pixel(i as u32 % img.width(), i as u32 / img.width(), color);
+            }
+        }
+    }
+    rejected.len()
+}
+
+// Combines the --height-map images into one brightness per tile, the highest of the maps winning.
+// None without any height map
+fn load_heights(args: &Args, width: u32, height: u32) -> Result<Option<Vec<u8>>> {
+    if args.height_map.is_empty() {
+        return Ok(None);
+    }
+    let mut heights = vec![0u8; (width * height) as usize];
+    for height_path in &args.height_map {
+        for (max, p) in heights.iter_mut().zip(load_aux_image(height_path, args, width, height)?.pixels()) {
+            *max = (*max).max(p.to_luma()[0]);
+        }
+    }
+    Ok(Some(heights))
+}
+
+// Heights for the previews: the height maps, else the depth map inverted so deep water is low, else none
+fn preview_heights(args: &Args, width: u32, height: u32) -> Result<Option<Vec<u8>>> {
+    if let Some(heights) = load_heights(args, width, height)? {
+        return Ok(Some(heights));
+    }
+    match &args.depth_map {
+        Some(depth_path) => Ok(Some(load_aux_image(depth_path, args, width, height)?.pixels().map(|p| 255 - p.to_luma()[0]).collect())),
+        None => Ok(None),
+    }
+}
+
+// Rewrites template tileMap entries of the form {"id": "..."} as the bare id strings the game reads,
+// failing on any other entry instead of dropping it and shifting every later index
+fn normalize_tile_map(map_data: &mut Value) -> Result<()> {
+    let Some(tile_map) = map_data.get_mut("tileMap").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    for (i, entry) in tile_map.iter_mut().enumerate() {
+        if entry.is_string() {
+            continue;
+        }
+        let id = entry.get("id").and_then(Value::as_str)
+            .with_context(|| format!("tileMap entry {} is {} instead of an id string or an object with an \"id\" string", i, entry))?;
+        *entry = json!(id);
+    }
+    Ok(())
+}
+
+// Decodes the template's existing tiles into a row-major grid of tile ids, top row first
+fn decode_template(map_data: &Value, width: u32, height: u32) -> Result<Vec<String>> {
+    let (cells_wide, cells_high) = (map_data["width"].as_u64(), map_data["height"].as_u64());
+    if cells_wide != Some(width as u64 / 64) || cells_high != Some(height as u64 / 64) {
+        anyhow::bail!(
+            "Template map is {:?}x{:?} cells but the image needs {}x{}, keeping template tiles requires a template of the same size",
+            cells_wide, cells_high, width / 64, height / 64
+        );
+    }
+    Ok(decode_tiles(map_data, "Template")?.2)
+}
+
+// Decodes a map's tiles into its size in tiles and a row-major grid of tile ids, top row first
+fn decode_tiles(map_data: &Value, name: &str) -> Result<(u32, u32, Vec<String>)> {
+    let map_data = wbox::join_chunks(map_data)?;
+    let cells = |key: &str| map_data[key].as_u64().with_context(|| format!("{} has no {}", name, key));
+    let (width, height) = (cells("width")? as u32 * 64, cells("height")? as u32 * 64);
+    let tile_map: Vec<&str> = map_data["tileMap"].as_array()
+        .with_context(|| format!("{} has no tileMap array", name))?
+        .iter()
+        .map(|v| v.as_str().with_context(|| format!("{} tileMap entries must be strings", name)))
+        .collect::<Result<_>>()?;
+    let rows = |key: &str| -> Result<Vec<Vec<usize>>> {
+        serde_json::from_value(map_data[key].clone()).with_context(|| format!("{} {} is not a list of rows", name, key))
+    };
+    let grid = rle_decode(&rows("tileArray")?, &rows("tileAmounts")?, width as usize)?;
+    if grid.len() != (width * height) as usize {
+        anyhow::bail!("{} tileArray covers {} tiles instead of {}", name, grid.len(), width * height);
+    }
+    let ids = grid.into_iter()
+        .map(|t| tile_map.get(t).map(|id| id.to_string()).with_context(|| format!("{} tile index {} is not in its tileMap", name, t)))
+        .collect::<Result<_>>()?;
+    Ok((width, height, ids))
+}
+
+// Matching options shared by conversions and LUT building
+fn quantize_options(args: &Args) -> QuantizeOptions {
+    QuantizeOptions {
+        locked_ids: args.lock_ids.iter().cloned().collect(),
+        color_space: args.color_space,
+        variation_k: args.variation_k,
+        seed: args.seed,
+        lut: None,
+        clamp_gamut: args.clamp_gamut,
+    }
+}
+
+// Flat index of a grid tile (row-major from the top-left) in the order tileArray is traversed: rows from
+// the bottom one up, like the RLE rows and object coordinates. frozen_tiles uses these indices.
+// Computed in u64, which holds the tile count of any grid that fits in memory
+fn traversal_index(i: usize, width: u32, height: u32) -> u64 {
+    let (x, y) = (i as u64 % width as u64, i as u64 / width as u64);
+    (height as u64 - 1 - y) * width as u64 + x
+}
+
+// Loads a palette file, or the --palette-inline entries when the palette is the inline one,
+// merging close colors with --merge-threshold
+fn load_palette(args: &Args, palette_path: &str) -> Result<Palette> {
+    let palette = match &args.palette_inline {
+        Some(inline) if palette_path == INLINE_PALETTE => {
+            let entries = inline.split(';').map(str::trim).filter(|e| !e.is_empty()).map(|entry| {
+                let (id, hex) = entry.split_once(char::is_whitespace)
+                    .with_context(|| format!("Invalid --palette-inline entry \"{}\", expected \"id #hex\"", entry))?;
+                let (r, g, b) = parse_hex(hex.trim())
+                    .map_err(|reason| anyhow::anyhow!("Invalid --palette-inline entry \"{}\": {}", entry, reason))?;
+                Ok((id.to_string(), [r, g, b]))
+            }).collect::<Result<_>>()?;
+            Palette::from_marked_entries(entries)?
+        }
+        _ => load_palette_file(palette_path, &args.csv_columns, args.palette_alpha)?,
+    };
+    // Special tiles come after the palette's own, which wins for ids and colors both have
+    let palette = if args.include_special {
+        let special = load_palette_file(&args.special_palette, &args.csv_columns, args.palette_alpha)?;
+        let own = palette.len();
+        let (palette, skipped) = palette.append(special)?;
+        status!("Special tiles of {} included: {} ids added, {} already in {}", args.special_palette, palette.len() - own, skipped.len(), palette_path);
+        palette
+    } else {
+        palette
+    };
+    let Some(threshold) = args.merge_threshold else {
+        return Ok(palette);
+    };
+    if !threshold.is_finite() || threshold < 0.0 {
+        anyhow::bail!("--merge-threshold {} must be a squared distance of 0 or more", threshold);
+    }
+    let (palette, merged) = palette.merge_close(threshold, args.color_space)?;
+    for entry in &merged {
+        eprintln!("Warning: {} merged {} into {} (squared distance {:.2})", palette_path, entry.id, entry.into, entry.distance);
+    }
+    if !merged.is_empty() {
+        status!("{} palette entries merged, {} left", merged.len(), palette.len());
+    }
+    Ok(palette)
+}
+
+// The worldLaws entries of a world laws file in file order, and the lines skipped for having no separator
+fn parse_world_laws(content: &str) -> (Vec<Value>, Vec<&str>) {
+    let mut skipped = Vec::new();
+    let laws = clean_lines(content)
+        .filter(|l| !l.is_empty())
+        .filter_map(|l| {
+            let law = split_world_law(l);
+            if law.is_none() {
+                skipped.push(l);
+            }
+            law
+        })
+        .map(|(k, v)| world_law(k, v))
+        .collect();
+    (laws, skipped)
+}
+
+// Appends the laws of the --world-laws file to the template's worldLaws list
+fn append_world_laws(args: &Args, map_data: &mut Value) -> Result<()> {
+    let laws = fs::read_to_string(&args.world_laws)?;
+    let list = {
+        if let Some(list) = map_data.get_mut("worldLaws")
+            .and_then(|wl| wl.get_mut("list"))
+            .and_then(Value::as_array_mut) {
+            list
+        } else {
+            map_data["worldLaws"] = json!({ "list": [] });
+            map_data["worldLaws"]["list"].as_array_mut().unwrap()
+        }
+    };
+    let (file_laws, skipped) = parse_world_laws(&laws);
+    for line in skipped {
+        eprintln!("Warning: skipping world law line without a separator in {}: {}", args.world_laws, line);
+    }
+    list.extend(file_laws);
+
+    // Command-line laws win over the template's and the file's: the first entry of the name is replaced
+    // in place, any later duplicate removed
+    let known = match &args.law_names {
+        Some(path) => {
+            let names = fs::read_to_string(path).with_context(|| format!("Failed to read law names: {}", path))?;
+            Some(clean_lines(&names).filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| split_world_law(l).map_or(l, |(name, _)| name).to_string())
+                .collect::<HashSet<String>>())
+        }
+        None => None,
+    };
+    for (name, value) in &args.law {
+        if let Some(known) = &known && !known.contains(name) {
+            eprintln!("Warning: --law {} is not a known world law name in {}", name, args.law_names.as_deref().unwrap_or_default());
+        }
+        let law = world_law(name, value);
+        let mut entries = list.iter().enumerate().filter(|(_, entry)| entry["name"] == json!(name)).map(|(i, _)| i);
+        match entries.next() {
+            Some(first) => {
+                let later: Vec<usize> = entries.collect();
+                list[first] = law;
+                for i in later.into_iter().rev() {
+                    list.remove(i);
+                }
+            }
+            None => list.push(law),
+        }
+        status!("World law {} set to {}", name, value);
+    }
+    Ok(())
+}
+
+// Loads a palette file, warning about every line that was skipped. A .csv palette's columns are
+// the --csv-columns pair when given
+fn load_palette_file(path: &str, csv_columns: &[String], alpha: bool) -> Result<Palette> {
+    let columns = match csv_columns {
+        [id, hex] => (id.as_str(), hex.as_str()),
+        _ => DEFAULT_CSV_COLUMNS,
+    };
+    let palette = if remote::is_url(path) {
+        // The query string would hide a .csv extension
+        let name = path.split(['?', '#']).next().unwrap_or(path);
+        Palette::load_data(name, remote::fetch(path)?, columns, alpha)?
+    } else if alpha {
+        Palette::load_with_alpha(path, columns)?
+    } else {
+        Palette::load_with_columns(path, columns)?
+    };
+    for warning in &palette.warnings {
+        eprintln!("Warning: {} {}, skipped", path, warning);
+    }
+    Ok(palette)
+}
+
+// Precomputes a LUT for every palette, named after the palette when there are several
+fn build_luts(args: &Args, lut_path: &str) -> Result<()> {
+    for palette_path in &args.palette {
+        let start = Instant::now();
+        let palette = load_palette(args, palette_path)?;
+        let options = quantize_options(args);
+        let lut = Lut::build(&palette, &options, args.lut_step)?;
+        let path = if args.palette.len() > 1 { suffixed_path(lut_path, file_stem(palette_path)) } else { lut_path.to_string() };
+        lut.save(&path, &palette, &options).with_context(|| format!("Failed to write LUT: {}", path))?;
+        status!("LUT for {} (step {}, {} KB) written to {} in {:?}", palette_path, lut.step(), lut.size_bytes() / 1024, path, start.elapsed());
+    }
+    Ok(())
+}
+
+// The sea level for a tiered palette: --sea-level as given, else picked from the image's histogram with
+// --auto-sea-level, else none and the tiers stay as written
+fn sea_level(args: &Args, img: &RgbImage) -> Option<u8> {
+    if let Some(level) = args.sea_level {
+        if args.auto_sea_level {
+            status!("Sea level {} given, not picking one from the histogram", level);
+        }
+        return Some(level);
+    }
+    if !args.auto_sea_level {
+        return None;
+    }
+    let histogram = tiers::luma_histogram(img);
+    let level = match args.sea_percentile {
+        Some(percent) => tiers::percentile_level(&histogram, percent),
+        None => tiers::otsu_level(&histogram),
+    };
+    let method = args.sea_percentile.map_or("the histogram's valley".to_string(), |p| format!("the {}th percentile", p));
+    status!("Auto sea level: {} ({})", level, method);
+    Some(level)
+}
+
+// Maps the image onto a single palette and writes the resulting .wbox, returning the preview image
+fn convert(args: &Args, source: &Source, palette_path: &str, outputs: &Outputs) -> Result<RgbImage> {
+    let start = Instant::now();
+    let mut img = source.img.clone();
+
+    let (palette, tiers) = match &args.tiered_palette {
+        Some(tiered_path) => {
+            let mut tiered = TieredPalette::load(tiered_path)?;
+            if let Some(level) = sea_level(args, &img) {
+                tiered.set_sea_level(level).with_context(|| format!("Can't use sea level {} with {}", level, tiered_path))?;
+                let water = tiers::luma_histogram(&img)[..=level as usize].iter().sum::<u64>();
+                status!("Sea level {}: {:.1}% of the pixels are {}, tiers now end at {:?}", level,
+                    water as f64 * 100.0 / (img.width() as f64 * img.height() as f64), tiered.palette.ids[0], tiered.thresholds);
+            }
+            let TieredPalette { palette, thresholds } = tiered;
+            (palette, Some(thresholds))
+        }
+        None => (load_palette(args, palette_path)?, None),
+    };
+    status!("Palette {} loaded in {:?}", palette_path, start.elapsed());
+    check_budget("loading the palette")?;
+
+    // Load the map_data JSON template, a preview on its own never touches it
+    let mut map_data: Value = if args.preview_only {
+        Value::Null
+    } else {
+        read_template(&args.map_data)?.parse().context("JSON parse error")?
+    };
+    normalize_tile_map(&mut map_data).with_context(|| format!("Unexpected tileMap in {}", args.map_data))?;
+
+    let mut options = quantize_options(args);
+    if let Some(lut_path) = &args.lut {
+        options.lut = Some(Arc::new(Lut::load(lut_path, &palette, &options)?));
+        status!("LUT {} loaded in {:?}", lut_path, start.elapsed());
+    }
+    if args.fast_match {
+        let lut = Lut::build(&palette, &options, FAST_MATCH_STEP)?;
+        status!("Fast match table of {} KB built in {:?}", lut.size_bytes() / 1024, start.elapsed());
+        options.lut = Some(Arc::new(lut));
+    }
+
+    // Tile grid, one entry per pixel (row-major from the top-left) as an index into tile_ids.
+    // tile_ids starts as the palette ids, layers may append ids the palette doesn't have
+    let (w, h) = img.dimensions();
+    let mut tile_ids = palette.ids.clone();
+    let mut grid = match &tiers {
+        Some(thresholds) => tiers::match_tiers(&img, thresholds),
+        None => match_pixels(&img, &palette, &options)?,
+    };
+
+    // Match quality of the color matches alone, before any layer replaces tiles
+    if let Some(quality_path) = &outputs.quality_map {
+        let distances = quality::matched_distances(&img, &grid, &palette, options.color_space);
+        let exact = distances.iter().filter(|&&d| d == 0.0).count();
+        let mean = distances.iter().map(|d| d.sqrt()).sum::<f64>() / distances.len() as f64;
+        let farthest = distances.iter().copied().fold(0.0, f64::max).sqrt();
+        save_image(&quality::heatmap(&distances, w, h), quality_path, args.preview_quality)
+            .with_context(|| format!("Failed to save {}", quality_path))?;
+        status!(
+            "Quality map saved to {}: {:.1}% exact matches, mean distance {:.2}, farthest {:.2}",
+            quality_path, exact as f64 * 100.0 / distances.len() as f64, mean, farthest
+        );
+    }
+
+    // Replace each pixel with its matched palette color
+    apply_grid(&mut img, &grid, &palette);
+
+    // Colors the policy refuses to force into the palette take the background tile instead
+    let rejected = reject_matches(args, &source.img, &palette, &mut grid, &mut tile_ids, &mut img);
+    if rejected > 0 {
+        status!("Colors too far from the palette, background tiles placed: {}", rejected);
+    }
+    status!("Image processed in {:?}", start.elapsed());
+    check_budget("matching colors")?;
+
+    // Show how the random picks spread over the palette, most used id first
+    if args.variation_k > 1 {
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for &idx in &grid {
+            *counts.entry(idx).or_default() += 1;
+        }
+        let mut counts: Vec<(usize, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        status!("Tile distribution with variation {}:", args.variation_k);
+        for (idx, count) in counts {
+            status!("  {:<32} {:>10} ({:.1}%)", palette.ids[idx], count, count as f64 * 100.0 / grid.len() as f64);
+        }
+    }
+
+    // Water pixels are split into depth tiles. This runs before the other layers since it only refines
+    // the color matches, template tiles and layers placed afterwards are never changed by it
+    if !args.depth_tiles.is_empty() {
+        let tiles = args.depth_tiles.len();
+        let thresholds = band_thresholds("depth", tiles, tiles - 1, &args.depth_thresholds)?;
+        let region: Vec<bool> = match &args.water_mask {
+            Some(mask_path) => load_aux_image(mask_path, args, w, h)?.pixels().map(|p| p.0 != [0, 0, 0]).collect(),
+            None => {
+                let water: HashSet<usize> = args.water_ids.iter()
+                    .filter_map(|id| tile_ids.iter().position(|t| t == id))
+                    .collect();
+                grid.iter().map(|t| water.contains(t)).collect()
+            }
+        };
+        let depth: Vec<u8> = if let Some(depth_path) = &args.depth_map {
+            load_aux_image(depth_path, args, w, h)?.pixels().map(|p| p.to_luma()[0]).collect()
+        } else if let Some(height) = load_heights(args, w, h)? {
+            height.into_iter().map(|v| 255 - v).collect()
+        } else {
+            source.img.pixels().map(|p| 255 - p.to_luma()[0]).collect()
+        };
+        let tiles: Vec<usize> = args.depth_tiles.iter().map(|id| tile_index(&mut tile_ids, id)).collect();
+        let mut counts = vec![0; tiles.len()];
+        for i in (0..grid.len()).filter(|&i| region[i]) {
+            let level = thresholds.iter().filter(|&&t| depth[i] >= t).count();
+            grid[i] = tiles[level];
+            if tiles[level] < palette.len() {
+                img.put_pixel(i as u32 % w, i as u32 / w, Rgb(palette.color(tiles[level]).into()));
+            }
+            counts[level] += 1;
+        }
+        status!("Water depth tiles placed:");
+        for (id, count) in args.depth_tiles.iter().zip(counts) {
+            status!("  {:<32} {:>10}", id, count);
+        }
+    }
+
+    // Pixels near an ignored color take the fallback tile, or the template's tile without one.
+    // The transparent color and every layer below still overwrite them
+    if !args.ignore_color.is_empty() {
+        let ignored = args.ignore_color.chunks(2).map(|pair| {
+            let color = hex_to_rgb(&pair[0]).with_context(|| format!("Invalid ignore color: {}", pair[0]))?;
+            let tolerance: u8 = pair[1].parse().with_context(|| format!("Invalid ignore color tolerance (0-255): {}", pair[1]))?;
+            Ok((color, tolerance))
+        }).collect::<Result<Vec<_>>>()?;
+        let near = |p: &Rgb<u8>| ignored.iter().any(|&((r, g, b), tolerance)| {
+            [r, g, b].iter().zip(p.0).all(|(&c, v)| c.abs_diff(v) <= tolerance)
+        });
+        let template = match &args.ignore_fallback {
+            Some(_) => None,
+            None => Some(decode_template(&map_data, w, h).context("--ignore-color keeps the template's tiles without --ignore-fallback")?),
+        };
+        let fallback = args.ignore_fallback.as_ref().map(|id| tile_index(&mut tile_ids, id));
+        let mut ignored_tiles = 0;
+        for (i, p) in source.img.pixels().enumerate().filter(|(_, p)| near(p)) {
+            let tile = fallback.unwrap_or_else(|| tile_index(&mut tile_ids, &template.as_ref().unwrap()[i]));
+            grid[i] = tile;
+            let color = if tile < palette.len() { Rgb(palette.color(tile).into()) } else { *p };
+            img.put_pixel(i as u32 % w, i as u32 / w, color);
+            ignored_tiles += 1;
+        }
+        status!("Ignored color tiles replaced: {}", ignored_tiles);
+    }
+
+    // Pixels of the transparent color keep the template's tile. This runs before any layer,
+    // so background, river and other layers still overwrite those pixels
+    if let Some(hex) = &args.transparent_color {
+        let (r, g, b) = hex_to_rgb(hex).with_context(|| format!("Invalid transparent color: {}", hex))?;
+        let template = decode_template(&map_data, w, h)?;
+        let mut kept = 0;
+        for (i, p) in source.img.pixels().enumerate().filter(|(_, p)| p.0 == [r, g, b]) {
+            let tile = tile_index(&mut tile_ids, &template[i]);
+            grid[i] = tile;
+            let color = if tile < palette.len() { Rgb(palette.color(tile).into()) } else { *p };
+            img.put_pixel(i as u32 % w, i as u32 / w, color);
+            kept += 1;
+        }
+        status!("Template tiles kept: {}", kept);
+    }
+
+    // Transparent pixels (alpha below 128) and padding take the background tile instead of their color match.
+    // With shore tiles, partly transparent pixels take the shore tile of their alpha band and
+    // only alpha below the first shore threshold is background
+    if let Some(alpha) = &source.alpha {
+        let shore_thresholds = band_thresholds("shore", args.shore_tiles.len(), args.shore_tiles.len(), &args.shore_thresholds)?;
+        let background = tile_index(&mut tile_ids, args.background_id.as_deref().unwrap_or(DEFAULT_BACKGROUND_ID));
+        let mut bands = vec![background];
+        bands.extend(args.shore_tiles.iter().map(|id| tile_index(&mut tile_ids, id)));
+        let cutoff = shore_thresholds.first().copied().unwrap_or(128);
+        let mut counts = vec![0; bands.len()];
+        for (i, &a) in alpha.iter().enumerate().filter(|&(_, &a)| a < cutoff || (bands.len() > 1 && a < 255)) {
+            let level = shore_thresholds.iter().filter(|&&t| a >= t).count();
+            grid[i] = bands[level];
+            if bands[level] < palette.len() {
+                img.put_pixel(i as u32 % w, i as u32 / w, Rgb(palette.color(bands[level]).into()));
+            }
+            counts[level] += 1;
+        }
+        status!("Background tiles placed: {}", counts[0]);
+        if !args.shore_tiles.is_empty() {
+            status!("Shore tiles placed:");
+            for (id, count) in args.shore_tiles.iter().zip(&counts[1..]) {
+                status!("  {:<32} {:>10}", id, count);
+            }
+        }
+    }
+
+    // Optionally overwrite tiles with rivers from the river mask
+    if let Some(river_map_path) = &args.river_map {
+        status!("Processing river map: {}", river_map_path);
+        let river_img = load_aux_image(river_map_path, args, w, h)?;
+        let mut mask: Vec<bool> = river_img.pixels().map(|p| p.0 != [0, 0, 0]).collect();
+        if args.thin_rivers {
+            thin_mask(&mut mask, w as usize, h as usize);
+        }
+        let river = tile_index(&mut tile_ids, &args.river_id);
+        // Recolor the preview when the river tile has a palette color
+        let color = (river < palette.len()).then(|| Rgb(palette.color(river).into()));
+        let mut river_tiles = 0;
+        for (i, _) in mask.iter().enumerate().filter(|(_, set)| **set) {
+            grid[i] = river;
+            if let Some(color) = color {
+                img.put_pixel(i as u32 % w, i as u32 / w, color);
+            }
+            river_tiles += 1;
+        }
+        status!("River tiles placed: {}", river_tiles);
+    }
+
+    // The border goes over every layer, so nothing placed above can break the frame
+    if let [id, _] = args.border.as_slice() {
+        let thickness = border_thickness(args)?;
+        let border = tile_index(&mut tile_ids, id);
+        let color = (border < palette.len()).then(|| Rgb(palette.color(border).into()));
+        let mut border_tiles = 0;
+        for i in (0..grid.len()).filter(|&i| in_border(i, w, h, thickness)) {
+            if grid[i] != border {
+                grid[i] = border;
+                border_tiles += 1;
+            }
+            if let Some(color) = color {
+                img.put_pixel(i as u32 % w, i as u32 / w, color);
+            }
+        }
+        status!("Border {} tiles wide placed: {} tiles overwritten with {}", thickness, border_tiles, id);
+    }
+
+    if let Some(max_runs) = args.max_runs {
+        limit_runs(args, max_runs, &mut grid, &mut img)?;
+    }
+
+    if args.preview_only {
+        save_previews(args, source, &img, outputs)?;
+        return Ok(img);
+    }
+
+    // The previews only read the finished image, so they are saved on a thread of their own while the
+    // map JSON is built and compressed. Both are always joined and a failure of either fails the run
+    let finish_start = Instant::now();
+    let (map_time, preview_time) = std::thread::scope(|scope| -> Result<(Duration, Duration)> {
+        let previews = scope.spawn(|| {
+            let start = Instant::now();
+            save_previews(args, source, &img, outputs).map(|()| start.elapsed())
+        });
+        let map_start = Instant::now();
+        let map = write_map(args, outputs, &palette, &img, &grid, &tile_ids, map_data).map(|()| map_start.elapsed());
+        let previews = previews.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        match (map, previews) {
+            (Ok(map), Ok(previews)) => Ok((map, previews)),
+            (Err(e), Ok(_)) | (Ok(_), Err(e)) => Err(e),
+            (Err(e), Err(preview_error)) => Err(e.context(format!("Saving the previews failed too: {:#}", preview_error))),
+        }
+    })?;
+    let finish = finish_start.elapsed();
+    status!(
+        "Map output ({:?}) and previews ({:?}) written side by side in {:?}, {:?} less than one after the other",
+        map_time, preview_time, finish, (map_time + preview_time).saturating_sub(finish)
+    );
+    Ok(img)
+}
+
+// Width of the --border frame in tiles, 0 without one
+fn border_thickness(args: &Args) -> Result<u32> {
+    match args.border.as_slice() {
+        [_, thickness] => thickness.parse()
+            .with_context(|| format!("--border thickness {} must be a whole number of tiles", thickness)),
+        _ => Ok(0),
+    }
+}
+
+// Whether tile `i` of a row-major grid lies within `thickness` tiles of the map's edge
+fn in_border(i: usize, width: u32, height: u32, thickness: u32) -> bool {
+    let (x, y) = (i as u32 % width, i as u32 / width);
+    x < thickness || y < thickness || x >= width.saturating_sub(thickness) || y >= height.saturating_sub(thickness)
+}
+
+// Keeps the map within --max-runs RLE runs in total: fails when it has more, or with --auto-simplify removes
+// ever larger speckles (regions of 2, 4, 8, ... tiles) until it fits, recoloring the preview to match
+fn limit_runs(args: &Args, max_runs: usize, grid: &mut [usize], img: &mut RgbImage) -> Result<()> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let mut runs = simplify::count_runs(grid, w);
+    if runs > max_runs && !args.auto_simplify {
+        anyhow::bail!("The map has {} RLE runs, more than --max-runs {}. Simplify the source or pass --auto-simplify", runs, max_runs);
+    }
+    if runs > max_runs {
+        // Every tile keeps the preview color it already has somewhere in the map
+        let mut colors: HashMap<usize, Rgb<u8>> = HashMap::new();
+        for (i, p) in img.pixels().enumerate() {
+            colors.entry(grid[i]).or_insert(*p);
+        }
+        let start = Instant::now();
+        let mut min_size = 2;
+        while runs > max_runs {
+            // Past half the map the largest region itself would be replaced, nothing simpler is left
+            if min_size > w * h / 2 {
+                anyhow::bail!("--auto-simplify couldn't get the map under --max-runs {}, {} runs are left", max_runs, runs);
+            }
+            let changed = simplify::remove_speckles(grid, w, h, min_size);
+            runs = simplify::count_runs(grid, w);
+            status!("Regions under {} tiles removed: {} tiles changed, {} runs left", min_size, changed, runs);
+            min_size *= 2;
+        }
+        for (i, p) in img.pixels_mut().enumerate() {
+            *p = colors[&grid[i]];
+        }
+        status!("Map simplified in {:?}", start.elapsed());
+    }
+    let widest = grid.chunks(w).map(|row| simplify::count_runs(row, w)).max().unwrap_or(0);
+    status!("RLE runs: {} of at most {}, the most in one row {}", runs, max_runs, widest);
+    Ok(())
+}
+
+// Saves the preview image and the optional isometric preview and minimap, all drawn from the finished preview
+fn save_previews(args: &Args, source: &Source, img: &RgbImage, outputs: &Outputs) -> Result<()> {
+    let start = Instant::now();
+    let (w, h) = img.dimensions();
+    // Contours go on a copy, the other previews and the map keep the plain tile colors
+    let contoured = match args.contours {
+        Some(interval) => {
+            let heights = preview_heights(args, w, h)?.context("--contours needs a --height-map or a --depth-map")?;
+            let mut contoured = img.clone();
+            let drawn = contour::draw(&mut contoured, &heights, interval);
+            status!("Contours every {} height levels drawn on {} tiles of the preview", interval, drawn);
+            Some(contoured)
+        }
+        None => None,
+    };
+    let preview = contoured.as_ref().unwrap_or(img);
+    // Save the processed image, optionally scaled back to the input's size for side-by-side comparison
+    let (original_width, original_height) = source.original_size;
+    if args.preview_original_size && img.dimensions() != source.original_size {
+        save_image(&resize(preview, original_width, original_height, imageops::FilterType::Nearest), &outputs.preview, args.preview_quality)
+    } else {
+        save_image(preview, &outputs.preview, args.preview_quality)
+    }
+    .with_context(|| format!("Failed to save {}", outputs.preview))?;
+    status!("Image saved in {:?}", start.elapsed());
+    check_budget("saving the preview")?;
+
+    // Optional shaded 2.5D view, raised by the height maps or an inverted depth map
+    if let Some(iso_path) = &outputs.iso_preview {
+        let heights = match preview_heights(args, w, h)? {
+            Some(heights) => heights,
+            None => {
+                status!("No --height-map or --depth-map given, the isometric preview is flat");
+                vec![0; (w * h) as usize]
+            }
+        };
+        save_image(&iso::render(img, &heights), iso_path, args.preview_quality)
+            .with_context(|| format!("Failed to save {}", iso_path))?;
+        status!("Approximate isometric preview saved to {}", iso_path);
+    }
+    // Optional difference to the source, every layer included, scaled so black is unchanged and white
+    // the largest possible RGB distance
+    if let Some(delta_path) = &outputs.delta_image {
+        let delta = quality::delta(&source.img, img);
+        let changed = delta.pixels().filter(|p| p[0] > 0).count();
+        save_image(&delta, delta_path, args.preview_quality).with_context(|| format!("Failed to save {}", delta_path))?;
+        status!("Delta image saved to {}: {:.1}% of the pixels changed", delta_path, changed as f64 * 100.0 / (w * h) as f64);
+    }
+    // Optional minimap, the dominant tile color of every area of the map
+    if let Some(minimap_path) = &outputs.minimap {
+        let size: u32 = args.minimap[1].parse().ok().filter(|&size| size > 0)
+            .with_context(|| format!("--minimap size {} must be a positive number of pixels", args.minimap[1]))?;
+        let minimap = sheet::minimap(img, size);
+        save_image(&minimap, minimap_path, args.preview_quality)
+            .with_context(|| format!("Failed to save {}", minimap_path))?;
+        status!("Minimap of {}x{} saved to {}", minimap.width(), minimap.height(), minimap_path);
+    }
+    Ok(())
+}
+
+// Adds the ids the grid uses to the template's tileMap. Ids it already lists keep their index, new ones are
+// appended in the order they first appear, so regenerating a map leaves the existing indices alone.
+// Returns how many ids the template listed and how many were appended
+fn append_tile_ids(tile_map: &mut Vec<Value>, grid: &[usize], tile_ids: &[String]) -> (usize, usize) {
+    let mut known: HashSet<String> = tile_map.iter().filter_map(|v| v.as_str().map(str::to_string)).collect();
+    let listed = known.len();
+    for &t in grid {
+        if known.insert(tile_ids[t].clone()) {
+            tile_map.push(json!(tile_ids[t]));
+        }
+    }
+    (listed, known.len() - listed)
+}
+
+// Without a tileMap (ex: a hand-written template) the map can't be loaded, so it is only started empty
+// with `create_missing`. Returns whether it was started
+fn ensure_tile_map(map_data: &mut Value, create_missing: bool, name: &str) -> Result<bool> {
+    if map_data["tileMap"].is_array() {
+        return Ok(false);
+    }
+    if !map_data.is_object() {
+        anyhow::bail!("Map data {} is not a JSON object", name);
+    }
+    if !create_missing {
+        anyhow::bail!("Map data {} has no tileMap array, rerun with --create-missing to start an empty one", name);
+    }
+    map_data["tileMap"] = json!([]);
+    Ok(true)
+}
+
+// Fills the map template with the tile grid (ids in `tile_ids`, palette entries first) and the other
+// layers of the run, then compresses it to the .wbox output
+fn write_map(
+    args: &Args, outputs: &Outputs, palette: &Palette, img: &RgbImage, grid: &[usize], tile_ids: &[String], mut map_data: Value,
+) -> Result<()> {
+    let start = Instant::now();
+    let (w, h) = img.dimensions();
+    let preserved = preserved_keys(args);
+    let kept: Vec<(&str, Option<Value>)> = preserved.iter().map(|&key| (key, map_data.get(key).cloned())).collect();
+    if ensure_tile_map(&mut map_data, args.create_missing, &args.map_data)? {
+        status!("No tileMap in {}, starting an empty one", args.map_data);
+    }
+    // Update map_data JSON
+    let tile_map = map_data["tileMap"].as_array_mut().expect("tileMap is an array after the check above");
+    let (listed, appended) = append_tile_ids(tile_map, grid, tile_ids);
+    status!("tileMap: {} ids reused from the template, {} appended", listed, appended);
+    if appended > 0 && preserved.contains(&"tileMap") {
+        let new: Vec<&str> = tile_map[tile_map.len() - appended..].iter().filter_map(Value::as_str).collect();
+        anyhow::bail!("tileMap is preserved but the map uses ids the template doesn't list: {}", new.join(", "));
+    }
+    
+    // Run-length Encoding for the tileArray and tileAmounts
+    // Don't ask me how it works I don't know
+    let tmap: Vec<_> = map_data["tileMap"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v.as_str().expect("tileMap entries are normalized to strings").to_string())
+        .collect();
+    // The first position wins if the template itself lists an id twice
+    let mut pidx = HashMap::new();
+    for (i, id) in tmap.iter().enumerate() {
+        pidx.entry(id).or_insert(i);
+    }
+
+    // Alpha of every tileMap index, parallel to tileMap. Ids without an alpha are opaque
+    if args.palette_alpha {
+        let alphas: Vec<u8> = tmap.iter()
+            .map(|id| palette.ids.iter().position(|p| p == id).and_then(|p| palette.alphas[p]).unwrap_or(255))
+            .collect();
+        status!("tileAlpha: {} of {} tileMap ids translucent", alphas.iter().filter(|&&a| a < 255).count(), alphas.len());
+        map_data["tileAlpha"] = json!(alphas);
+    }
+
+    // Optionally describe every tileMap index for external viewers
+    if let Some(legend_path) = &outputs.legend {
+        let legend: Vec<Value> = tmap.iter().enumerate().map(|(i, id)| {
+            let entry = palette.ids.iter().position(|p| p == id);
+            let color = entry.map(|p| {
+                let (r, g, b) = palette.color(p);
+                json!([r, g, b])
+            });
+            let mut item = json!({ "index": i, "id": id, "color": color });
+            if let Some(section) = entry.and_then(|p| palette.sections[p].as_ref()) {
+                item["section"] = json!(section);
+            }
+            item
+        }).collect();
+        let legend = serde_json::to_string_pretty(&legend)?;
+        write_atomic(legend_path, |temp| -> Result<()> { Ok(fs::write(temp, &legend)?) })
+            .with_context(|| format!("Failed to write legend: {}", legend_path))?;
+        status!("Legend written to {}", legend_path);
+    }
+
+    // Optionally save the palette entries the finished grid uses, in palette order
+    if let Some(used_path) = &outputs.used_palette {
+        let mut used = vec![false; palette.len()];
+        for &t in grid.iter().filter(|&&t| t < palette.len()) {
+            used[t] = true;
+        }
+        let entries: Vec<usize> = (0..palette.len()).filter(|&i| used[i]).collect();
+        let text = palette.to_text(&entries);
+        write_atomic(used_path, |temp| -> Result<()> { Ok(fs::write(temp, &text)?) })
+            .with_context(|| format!("Failed to write used palette: {}", used_path))?;
+        status!("Used palette written to {}: {} of {} palette ids", used_path, entries.len(), palette.len());
+    }
+
+    let cells: Vec<usize> = grid.iter()
+        .map(|&t| *pidx.get(&tile_ids[t]).expect("ID not in palette index"))
+        .collect();
+    let (tile_array, tile_amounts) = rle_encode(&cells, w as usize);
+
+    // Optionally save the tileMap index of every tile packed into its pixel color, for tools that re-read the grid
+    if let Some(index_path) = &outputs.index_image {
+        if matches!(ImageFormat::from_path(index_path)?, ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::Avif) {
+            anyhow::bail!("{} must use a lossless format such as .png, the index image stores numbers in its colors", index_path);
+        }
+        let index_image = RgbImage::from_fn(w, h, |x, y| {
+            let index = cells[(y * w + x) as usize];
+            Rgb([(index & 0xff) as u8, (index >> 8 & 0xff) as u8, (index >> 16 & 0xff) as u8])
+        });
+        save_image(&index_image, index_path, None).with_context(|| format!("Failed to save {}", index_path))?;
+        status!("Index image written to {}", index_path);
+    }
+
+    // Optionally save the grid for the Tiled editor, one tileset tile per tileMap index in the same order
+    if let (Some(tiled_path), Some(tileset_path)) = (&outputs.tiled, &outputs.tiled_tileset) {
+        if Path::new(tiled_path).extension().is_some_and(|e| e.eq_ignore_ascii_case("tmx")) {
+            anyhow::bail!("{} names a .tmx map, --export-tiled writes Tiled's JSON format, use a .json or .tmj path", tiled_path);
+        }
+        let mut first_use = vec![None; tmap.len()];
+        for (i, &cell) in cells.iter().enumerate() {
+            first_use[cell].get_or_insert(i);
+        }
+        let tiles: Vec<TiledTile> = tmap.iter().enumerate().map(|(index, id)| match palette.ids.iter().position(|p| p == id) {
+            Some(entry) => TiledTile { id: id.clone(), color: palette.color(entry).into(), from_palette: true },
+            None => {
+                let color = first_use[index].map_or([0, 0, 0], |i| img.get_pixel(i as u32 % w, i as u32 / w).0);
+                TiledTile { id: id.clone(), color, from_palette: false }
+            }
+        }).collect();
+        save_image(&tiled::tileset_image(&tiles), tileset_path, None).with_context(|| format!("Failed to save {}", tileset_path))?;
+        let map = tiled::tiled_map(&cells, w, h, &tiles, file_name(tileset_path));
+        let text = serde_json::to_string(&map)?;
+        write_atomic(tiled_path, |temp| -> Result<()> { Ok(fs::write(temp, &text)?) })
+            .with_context(|| format!("Failed to write Tiled map: {}", tiled_path))?;
+        status!("Tiled map written to {} with its tileset {}", tiled_path, tileset_path);
+    }
+
+    if let Some(problem) = audit_tile_amounts(&tile_amounts, w as usize, h as usize) {
+        if args.audit {
+            anyhow::bail!("Audit failed: {}", problem);
+        }
+        eprintln!("Warning: {}", problem);
+    }
+    let covered: usize = tile_amounts.iter().flatten().sum();
+    if args.rle_stats {
+        let runs = tile_amounts.iter().map(Vec::len).sum::<usize>();
+        let longest = tile_amounts.iter().flatten().max().copied().unwrap_or(0);
+        let single = tile_amounts.iter().flatten().filter(|&&n| n == 1).count();
+        status!(
+            "RLE stats: {} runs over {} tiles, average run {:.2} tiles, longest run {} tiles, {:.1}% single-tile runs",
+            runs, covered, covered as f64 / runs.max(1) as f64, longest, single as f64 * 100.0 / runs.max(1) as f64
+        );
+    }
+
+    map_data["height"] = json!(img.height() / 64);
+    map_data["width"] = json!(img.width() / 64);
+    // Placeholders keep the key order, the arrays themselves are streamed at compression time
+    if args.chunked_tiles.is_some() {
+        if let Some(object) = map_data.as_object_mut() {
+            object.remove("tileArray");
+            object.remove("tileAmounts");
+        }
+        map_data["chunks"] = Value::Null;
+    } else {
+        map_data["tileArray"] = Value::Null;
+        map_data["tileAmounts"] = Value::Null;
+    }
+
+    if preserved.contains(&"worldLaws") {
+        if !args.law.is_empty() {
+            anyhow::bail!("--law can't set world laws while worldLaws is kept as in the template");
+        }
+        status!("worldLaws kept from the template, {} not read", args.world_laws);
+    } else {
+        append_world_laws(args, &mut map_data)?;
+    }
+
+    // Name, description and author shown by the game, written into mapStats
+    for (key, value) in [("name", &args.map_name), ("description", &args.map_description), ("player_name", &args.author)] {
+        if let Some(value) = value {
+            if !map_data["mapStats"].is_object() {
+                map_data["mapStats"] = json!({});
+            }
+            map_data["mapStats"][key] = json!(value);
+        }
+    }
+
+    // Provenance in a key of its own the game doesn't read: the version that made the map and when,
+    // and how to reproduce it
+    let mut provenance = serde_json::Map::new();
+    if !args.no_stamp {
+        provenance.insert("tool".to_string(), json!(env!("CARGO_PKG_NAME")));
+        provenance.insert("version".to_string(), json!(env!("CARGO_PKG_VERSION")));
+        provenance.insert("timestamp".to_string(), json!(utc_timestamp(SystemTime::now())));
+    }
+    if args.embed_command {
+        provenance.insert("command".to_string(), json!(args.command_line));
+    }
+    if !provenance.is_empty() {
+        map_data["_herzmapper"] = Value::Object(provenance);
+    }
+
+    // Optionally process the freeze map images to add frozen_tiles to map_data
+    if !args.freeze_map.is_empty() {
+        // A tile is frozen when it is white (RGB == 255,255,255) in any of the freeze maps
+        let mut frozen = vec![false; grid.len()];
+        for freeze_map_path in &args.freeze_map {
+            status!("Processing freeze map: {}", freeze_map_path);
+            let freeze_img = load_aux_image(freeze_map_path, args, w, h)?;
+            for (set, pixel) in frozen.iter_mut().zip(freeze_img.pixels()) {
+                *set |= pixel.0 == [255, 255, 255];
+            }
+        }
+        // Border tiles are never frozen, whatever the freeze maps say
+        let thickness = border_thickness(args)?;
+        let frozen_grid: Vec<usize> = (0..frozen.len()).filter(|&i| frozen[i] && !in_border(i, w, h, thickness)).collect();
+        let mut frozen_tiles: Vec<u64> = frozen_grid.iter().map(|&i| traversal_index(i, w, h)).collect();
+        frozen_tiles.sort_unstable();
+        status!("Frozen tiles added: {}", frozen_tiles.len());
+
+        // Frozen hot tiles (lava, desert, ...) are most likely a misaligned or inverted freeze map
+        let hot: HashSet<&str> = args.hot_ids.iter().map(String::as_str).collect();
+        let mut conflicts: HashMap<&str, usize> = HashMap::new();
+        for &i in &frozen_grid {
+            let id = tile_ids[grid[i]].as_str();
+            if hot.contains(id) {
+                *conflicts.entry(id).or_default() += 1;
+            }
+        }
+        if !conflicts.is_empty() {
+            let mut conflicts: Vec<(&str, usize)> = conflicts.into_iter().collect();
+            conflicts.sort();
+            let list: Vec<String> = conflicts.iter().map(|(id, count)| format!("{} x{}", id, count)).collect();
+            let problem = format!("the freeze map freezes {} hot tile(s): {}", conflicts.iter().map(|(_, c)| c).sum::<usize>(), list.join(", "));
+            if args.strict {
+                anyhow::bail!("Inconsistent layers, {}", problem);
+            }
+            eprintln!("Warning: {}", problem);
+        }
+        map_data["frozen_tiles"] = json!(frozen_tiles);
+    }
+
+    // Optionally place objects from the marker image into mapObjects
+    if let Some(objects_path) = &args.objects {
+        status!("Processing objects: {}", objects_path);
+        let colors_path = args.object_colors.as_deref().context("--objects needs --object-colors")?;
+        let colors = load_palette_file(colors_path, &args.csv_columns, false)?;
+        let markers = load_aux_image(objects_path, args, w, h)?;
+        let objects: Vec<Value> = place_objects(&markers, &colors, args.object_placement).into_iter()
+            .map(|(x, y, object)| json!({ "x": x, "y": y, "id": colors.ids[object] }))
+            .collect();
+        status!("Objects placed: {}", objects.len());
+        map_data["mapObjects"] = json!(objects);
+    }
+
+    // Optionally add the resource amount of every tile, run-length encoded in rows like the tiles
+    let resources = match &args.resource_map {
+        Some(resource_path) => {
+            status!("Processing resource map: {}", resource_path);
+            let resource_img = load_aux_image(resource_path, args, w, h)?;
+            let amounts: Vec<usize> = resource_img.pixels()
+                .map(|p| (p.to_luma()[0] as f64 * args.resource_max as f64 / 255.0).round() as usize)
+                .collect();
+            let (resource_array, resource_amounts) = rle_encode(&amounts, w as usize);
+            status!(
+                "Resource {} added: {} tiles with some, {} in total, {} runs",
+                args.resource_name.as_deref().unwrap_or_default(), amounts.iter().filter(|&&a| a > 0).count(),
+                amounts.iter().sum::<usize>(), resource_amounts.iter().map(Vec::len).sum::<usize>()
+            );
+            map_data["resourceName"] = json!(args.resource_name);
+            map_data["resourceArray"] = Value::Null;
+            map_data["resourceAmounts"] = Value::Null;
+            Some((resource_array, resource_amounts))
+        }
+        None => None,
+    };
+
+    // Everything preserved goes back to the template's value, or away when the template didn't have it
+    for (key, value) in kept {
+        match value {
+            Some(value) => map_data[key] = value,
+            None => {
+                if let Some(object) = map_data.as_object_mut() {
+                    object.remove(key);
+                }
+            }
+        }
+    }
+    if !preserved.is_empty() {
+        status!("Kept as in the template: {}", preserved.join(", "));
+    }
+
+    status!("JSON updated in {:?}", start.elapsed());
+    check_budget("building the map JSON")?;
+
+    // Serialize JSON directly into the compressed output file
+    let mut document = WboxDocument {
+        map_data: &map_data,
+        layers: match args.chunked_tiles {
+            Some(rows) => HashMap::from([
+                ("chunks", Layer::Bands { rows: rows as usize, tile_array: &tile_array, tile_amounts: &tile_amounts }),
+            ]),
+            None => HashMap::from([
+                ("tileArray", Layer::Rows(&tile_array)),
+                ("tileAmounts", Layer::Rows(&tile_amounts)),
+            ]),
+        },
+        compact: !args.pretty_json,
+    };
+    if let Some((resource_array, resource_amounts)) = &resources {
+        document.layers.insert("resourceArray", Layer::Rows(resource_array));
+        document.layers.insert("resourceAmounts", Layer::Rows(resource_amounts));
+    }
+    if args.estimate_size {
+        let compress_start = Instant::now();
+        let (bytes, json_bytes) = compress_counted(&document, budget(), args.container, args.compression).context("Failed to compress output")?;
+        status!(
+            "Estimated size: {} bytes from {} bytes of JSON ({:.1}% of the JSON, {:.1}:1) in {:?}, {} not written",
+            bytes.len(), json_bytes, bytes.len() as f64 * 100.0 / json_bytes.max(1) as f64,
+            json_bytes as f64 / bytes.len().max(1) as f64, compress_start.elapsed(), outputs.wbox
+        );
+    } else if args.output_base64 {
+        let bytes = compress_wbox_to_vec(&document, budget(), args.container, args.compression).context("Failed to compress output")?;
+        println!("{}", BASE64_STANDARD.encode(&bytes));
+        status!("Compression successful. {} bytes written to stdout as base64", bytes.len());
+    } else {
+        compress_to_wbox_within(&document, &outputs.wbox, budget(), args.container, args.compression)
+            .with_context(|| format!("Failed to compress output to: {}", outputs.wbox))?;
+        status!("Compression successful. Output written to {}", outputs.wbox);
+    }
+
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_rounds_each_side_up_to_64_with_a_minimum_of_128() {
+        for (side, expected) in [(1, 128), (64, 128), (65, 128), (128, 128), (129, 192), (192, 192)] {
+            assert_eq!(resize_to_nearest_64(RgbImage::new(side, 1)).dimensions(), (expected, 128), "width {}", side);
+            assert_eq!(resize_to_nearest_64(RgbImage::new(1, side)).dimensions(), (128, expected), "height {}", side);
+        }
+    }
+
+    #[test]
+    fn audit_passes_on_a_non_square_grid_and_keeps_coordinates() {
+        // 3x5 map cells, an odd multiple of 64 on both sides
+        let (w, h) = (192, 320);
+        let cells: Vec<usize> = (0..w * h).map(|i| (i % w / 7 + i / w / 5) % 4).collect();
+        let (tile_array, tile_amounts) = rle_encode(&cells, w);
+        assert_eq!(audit_tile_amounts(&tile_amounts, w, h), None);
+        // Lists are bottom row first, so the tile at (x, y) comes from list h - 1 - y
+        for (x, y) in [(0, 0), (w - 1, 0), (5, h - 1), (w - 1, h - 1), (100, 77)] {
+            let (tiles, amounts) = (&tile_array[h - 1 - y], &tile_amounts[h - 1 - y]);
+            let run = amounts.iter().scan(0, |end, n| { *end += n; Some(*end) }).position(|end| end > x).unwrap();
+            assert_eq!(tiles[run], cells[y * w + x], "tile at {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn audit_reports_runs_that_miss_tiles() {
+        let (_, mut tile_amounts) = rle_encode(&[0, 0, 1, 2, 2, 2], 3);
+        assert_eq!(audit_tile_amounts(&tile_amounts, 3, 2), None);
+        tile_amounts[1][0] = 1;
+        assert_eq!(
+            audit_tile_amounts(&tile_amounts, 3, 2).as_deref(),
+            Some("tileAmounts cover 5 tiles in 2 rows (1 of the wrong width) but the image has 6 tiles in 2 rows")
+        );
+        assert!(audit_tile_amounts(&tile_amounts[..1], 3, 2).is_some());
+    }
+
+    #[test]
+    fn tile_source_rejects_sizes_that_overflow_or_get_too_large() {
+        let img = RgbImage::new(64, 64);
+        assert!(tile_image(&img, (10_000_000, 10_000_000), false).is_err());
+        assert!(tile_image(&img, (1, u32::MAX), false).is_err());
+        let error = tile_image(&img, (70_000, 70_000), false).unwrap_err().to_string();
+        assert!(error.contains("70000x70000 of a 64x64 image"), "{}", error);
+        assert_eq!(tile_image(&img, (2, 3), false).unwrap().dimensions(), (192, 128));
+        // One pixel over the limit
+        assert!(tile_image(&RgbImage::new(1, 1), (16384, 16385), false).is_err());
+    }
+
+    #[test]
+    fn sixteen_bit_gradient_rounds_to_the_nearest_8_bit_value() {
+        // Red steps by 256 and blue sits just past half an 8-bit level, where keeping the high byte and rounding disagree
+        let gradient = ImageBuffer::from_fn(256, 2, |x, _| image::Rgb([(x * 256) as u16, 65535 - (x * 256) as u16, 257 * x.min(254) as u16 + 129]));
+        let path = std::env::temp_dir().join(format!("herzmapper-test-{}-gradient16.png", std::process::id()));
+        gradient.save(&path).unwrap();
+        let decoded = load_input(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let decoded = decoded.unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb16);
+        let img = decoded.to_rgb8();
+        for (x, _, pixel) in img.enumerate_pixels() {
+            let nearest = gradient.get_pixel(x, 0).0.map(|c| (c as f64 / 257.0).round() as u8);
+            assert_eq!(pixel.0, nearest, "x = {}", x);
+        }
+        assert_eq!(img.get_pixel(255, 0).0, [254, 1, 255]);
+    }
+
+    #[test]
+    fn template_listing_the_used_ids_gets_no_appends() {
+        let tile_ids: Vec<String> = ["water", "sand", "grass"].map(String::from).to_vec();
+        let template = vec![json!("grass"), json!("sand"), json!("water"), json!("stone")];
+        let mut tile_map = template.clone();
+        assert_eq!(append_tile_ids(&mut tile_map, &[0, 1, 1, 2, 0], &tile_ids), (4, 0));
+        assert_eq!(tile_map, template);
+
+        // New ids go after the template's in order of first appearance, each once
+        let mut tile_map = vec![json!("sand")];
+        assert_eq!(append_tile_ids(&mut tile_map, &[2, 1, 0, 2, 0], &tile_ids), (1, 2));
+        assert_eq!(tile_map, [json!("sand"), json!("grass"), json!("water")]);
+    }
+
+    #[test]
+    fn world_laws_accept_mixed_separators() {
+        let content = "world_law_hunger true\nmutation=true\nworld_law_diseases:false\nworld_law_rebellions\ttrue\n \n  \
+            world_law_angry_civilians = false\nworld_law_kings: \ttrue\nnoseparator\nworld_law_rate 3\n";
+        let (laws, skipped) = parse_world_laws(content);
+        assert_eq!(laws, [
+            json!({ "name": "world_law_hunger" }),
+            json!({ "name": "mutation" }),
+            json!({ "name": "world_law_diseases", "boolVal": false }),
+            json!({ "name": "world_law_rebellions" }),
+            json!({ "name": "world_law_angry_civilians", "boolVal": false }),
+            json!({ "name": "world_law_kings" }),
+            // Numbers are typed like --law values
+            json!({ "name": "world_law_rate", "intVal": 3 }),
+        ]);
+        assert_eq!(skipped, ["noseparator"]);
+    }
+
+    #[test]
+    fn law_values_are_typed() {
+        assert_eq!(world_law("a", "TRUE"), json!({ "name": "a" }));
+        assert_eq!(world_law("a", "false"), json!({ "name": "a", "boolVal": false }));
+        assert_eq!(world_law("a", "-3"), json!({ "name": "a", "intVal": -3 }));
+        assert_eq!(world_law("a", "1.5"), json!({ "name": "a", "floatVal": 1.5 }));
+        assert_eq!(parse_law("a=2").unwrap(), ("a".to_string(), "2".to_string()));
+        assert!(parse_law("a=ture").is_err());
+        assert!(parse_law("=true").is_err());
+    }
+
+    #[test]
+    fn object_tile_map_entries_become_id_strings() {
+        let mut map_data = json!({ "tileMap": [{ "id": "101" }, "102", { "id": "103", "extra": 1 }] });
+        normalize_tile_map(&mut map_data).unwrap();
+        assert_eq!(map_data["tileMap"], json!(["101", "102", "103"]));
+
+        // Anything else fails with its position instead of being dropped
+        for entry in [json!(7), json!(null), json!({ "name": "101" }), json!({ "id": 101 })] {
+            let mut map_data = json!({ "tileMap": ["100", entry] });
+            let error = normalize_tile_map(&mut map_data).unwrap_err().to_string();
+            assert!(error.starts_with("tileMap entry 1 is"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn frozen_tile_indices_follow_the_tile_array_traversal() {
+        let (w, h) = (192u32, 320u32);
+        assert_eq!(traversal_index(0, w, h), 61248);
+        assert_eq!(traversal_index(191, w, h), 61439);
+        assert_eq!(traversal_index((319 * 192 + 5) as usize, w, h), 5);
+
+        // Expanding the RLE rows in file order puts every tile at its traversal index
+        let (w, h) = (7u32, 5u32);
+        let grid: Vec<usize> = (0..35).map(|i| i * 3 % 4).collect();
+        let (tile_array, tile_amounts) = rle_encode(&grid, w as usize);
+        let traversed: Vec<usize> = tile_array.iter().zip(&tile_amounts)
+            .flat_map(|(tiles, amounts)| tiles.iter().zip(amounts).flat_map(|(&t, &n)| std::iter::repeat_n(t, n)))
+            .collect();
+        for (i, &tile) in grid.iter().enumerate() {
+            assert_eq!(traversed[traversal_index(i, w, h) as usize], tile, "tile {}", i);
+        }
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn frozen_tile_indices_hold_the_largest_grid() {
+        // The widest and tallest grid of whole cells, 2^64 - 2^39 + 2^12 tiles
+        let side = u32::MAX / 64 * 64;
+        let tiles = side as u64 * side as u64;
+        assert_eq!(traversal_index(0, side, side), tiles - side as u64);
+        assert_eq!(traversal_index((tiles - 1) as usize, side, side), side as u64 - 1);
+        assert_eq!(traversal_index((tiles - side as u64) as usize, side, side), 0);
+    }
+
+    #[test]
+    fn template_without_tile_map_needs_create_missing() {
+        let template = json!({ "width": 2, "height": 2, "worldLaws": { "list": [] } });
+        let error = ensure_tile_map(&mut template.clone(), false, "map_data.json").unwrap_err().to_string();
+        assert_eq!(error, "Map data map_data.json has no tileMap array, rerun with --create-missing to start an empty one");
+
+        let mut map_data = template.clone();
+        assert!(ensure_tile_map(&mut map_data, true, "map_data.json").unwrap());
+        assert_eq!(map_data["tileMap"], json!([]));
+        assert_eq!(map_data["worldLaws"], template["worldLaws"]);
+        // An existing tileMap is left alone, a template that isn't an object always fails
+        assert!(!ensure_tile_map(&mut map_data, false, "map_data.json").unwrap());
+        assert!(ensure_tile_map(&mut json!([1, 2]), true, "map_data.json").is_err());
+        assert!(ensure_tile_map(&mut json!({ "tileMap": "grass" }), false, "map_data.json").is_err());
+    }
+}
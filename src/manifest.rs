@@ -0,0 +1,136 @@
+// Manifest files describing many conversion jobs, so a set of maps can be rebuilt from one declarative file
+use std::{fs, path::Path};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use herzmapper::ColorSpace;
+use crate::{file_stem, Args, Flip, Job};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    jobs: Vec<Entry>,
+}
+
+// A single value or a list, so "palette": "all.txt" works as well as a list of palettes
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+// One job of the manifest. Keys are the long command-line flag names, anything left out
+// falls back to the value given on the command line
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Entry {
+    input: String,
+    palette: Option<OneOrMany>,
+    map_data: Option<String>,
+    output: Option<String>,
+    world_laws: Option<String>,
+    map_name: Option<String>,
+    map_description: Option<String>,
+    author: Option<String>,
+    freeze_map: Option<OneOrMany>,
+    river_map: Option<String>,
+    river_id: Option<String>,
+    thin_rivers: Option<bool>,
+    water_mask: Option<String>,
+    depth_map: Option<String>,
+    height_map: Option<OneOrMany>,
+    objects: Option<String>,
+    object_colors: Option<String>,
+    background_id: Option<String>,
+    transparent_color: Option<String>,
+    color_space: Option<String>,
+    lock_ids: Option<OneOrMany>,
+    legend: Option<String>,
+    variation_k: Option<usize>,
+    seed: Option<u64>,
+    flip: Option<String>,
+    preview_original_size: Option<bool>,
+}
+
+// Reads a manifest (TOML for .toml files, JSON otherwise) into jobs that start from the command-line
+// arguments. Relative paths in the manifest are relative to the manifest file itself
+pub fn load(path: &str, base: &Args) -> Result<Vec<Job>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read manifest: {}", path))?;
+    let manifest: Manifest = if Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case("toml")) {
+        toml::from_str(&content).with_context(|| format!("Invalid TOML manifest: {}", path))?
+    } else {
+        serde_json::from_str(&content).with_context(|| format!("Invalid JSON manifest: {}", path))?
+    };
+    if manifest.jobs.is_empty() {
+        anyhow::bail!("Manifest {} lists no jobs", path);
+    }
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let resolve = |p: String| dir.join(p).to_string_lossy().into_owned();
+    let several = manifest.jobs.len() > 1;
+    manifest.jobs.into_iter().enumerate().map(|(i, entry)| {
+        let input = resolve(entry.input);
+        let mut args = base.clone();
+        let own_output = entry.output.is_some();
+        if let Some(palette) = entry.palette {
+            args.palette = palette.into_vec().into_iter().map(resolve).collect();
+            args.palette_inline = None;
+        }
+        if let Some(map_data) = entry.map_data {
+            args.map_data = resolve(map_data);
+        }
+        if let Some(output) = entry.output {
+            args.output = resolve(output);
+        }
+        if let Some(world_laws) = entry.world_laws {
+            args.world_laws = resolve(world_laws);
+        }
+        args.map_name = entry.map_name.or(args.map_name);
+        args.map_description = entry.map_description.or(args.map_description);
+        args.author = entry.author.or(args.author);
+        if let Some(freeze_map) = entry.freeze_map {
+            args.freeze_map = freeze_map.into_vec().into_iter().map(resolve).collect();
+        }
+        args.river_map = entry.river_map.map(resolve).or(args.river_map);
+        args.river_id = entry.river_id.unwrap_or(args.river_id);
+        args.thin_rivers = entry.thin_rivers.unwrap_or(args.thin_rivers);
+        args.water_mask = entry.water_mask.map(resolve).or(args.water_mask);
+        args.depth_map = entry.depth_map.map(resolve).or(args.depth_map);
+        if let Some(height_map) = entry.height_map {
+            args.height_map = height_map.into_vec().into_iter().map(resolve).collect();
+        }
+        args.objects = entry.objects.map(resolve).or(args.objects);
+        args.object_colors = entry.object_colors.map(resolve).or(args.object_colors);
+        args.background_id = entry.background_id.or(args.background_id);
+        args.transparent_color = entry.transparent_color.or(args.transparent_color);
+        if let Some(space) = entry.color_space {
+            args.color_space = ColorSpace::from_str(&space, true)
+                .map_err(|e| anyhow::anyhow!("Job {} in {}: invalid color-space {}: {}", i + 1, path, space, e))?;
+        }
+        if let Some(lock_ids) = entry.lock_ids {
+            args.lock_ids = lock_ids.into_vec();
+        }
+        args.legend = entry.legend.map(resolve).or(args.legend);
+        args.variation_k = entry.variation_k.unwrap_or(args.variation_k);
+        args.seed = entry.seed.unwrap_or(args.seed);
+        if let Some(flip) = entry.flip {
+            args.flip = Flip::from_str(&flip, true)
+                .map_err(|e| anyhow::anyhow!("Job {} in {}: invalid flip {}: {}", i + 1, path, flip, e))?;
+        }
+        args.preview_original_size = entry.preview_original_size.unwrap_or(args.preview_original_size);
+
+        // Like a batch, several jobs get the input's name in their outputs so they don't overwrite each other
+        let suffix = several.then(|| file_stem(&input).to_string());
+        Ok(Job { args, input, suffix, own_output })
+    }).collect()
+}
@@ -0,0 +1,81 @@
+// Seeded gradient noise, the terrain of maps generated without a source image
+use rayon::prelude::*;
+use crate::quantize::unit_random;
+
+// Octaves of noise summed into the terrain, each twice the frequency and half the weight of the last
+const OCTAVES: u32 = 5;
+
+// Heights of a width x height grid (row-major) from fractal Perlin noise, scaled so the lowest tile is 0
+// and the highest 1. `feature_size` is the size in tiles of the largest landmasses. The same seed,
+// size and feature size always give the same heights
+pub fn heightmap(width: u32, height: u32, seed: u64, feature_size: f64) -> Vec<f64> {
+    let perlin = Perlin::new(seed);
+    let mut heights: Vec<f64> = (0..width * height).into_par_iter().map(|i| {
+        let (x, y) = ((i % width) as f64 / feature_size, (i / width) as f64 / feature_size);
+        (0..OCTAVES).map(|octave| {
+            let frequency = (1u32 << octave) as f64;
+            perlin.at(x * frequency, y * frequency) / frequency
+        }).sum()
+    }).collect();
+    let (min, max) = heights.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &h| (lo.min(h), hi.max(h)));
+    let range = (max - min).max(f64::EPSILON);
+    heights.iter_mut().for_each(|h| *h = (*h - min) / range);
+    heights
+}
+
+// Classic 2D Perlin noise over a seeded permutation of the 256 lattice hashes
+struct Perlin {
+    permutation: [u8; 512],
+}
+
+impl Perlin {
+    fn new(seed: u64) -> Perlin {
+        let mut table: Vec<u8> = (0..=255).collect();
+        // Fisher-Yates shuffle driven by the seed
+        for i in (1..table.len()).rev() {
+            let j = (unit_random(seed, i as u64) * (i + 1) as f64) as usize;
+            table.swap(i, j);
+        }
+        let mut permutation = [0; 512];
+        for (i, p) in permutation.iter_mut().enumerate() {
+            *p = table[i % 256];
+        }
+        Perlin { permutation }
+    }
+
+    // Noise at a point, roughly in [-1, 1] and 0 on every lattice point
+    fn at(&self, x: f64, y: f64) -> f64 {
+        let (cell_x, cell_y) = (x.floor(), y.floor());
+        let (xi, yi) = ((cell_x as i64 & 255) as usize, (cell_y as i64 & 255) as usize);
+        let (fx, fy) = (x - cell_x, y - cell_y);
+        let p = &self.permutation;
+        let hash = |dx: usize, dy: usize| p[p[xi + dx] as usize + yi + dy];
+        let (u, v) = (fade(fx), fade(fy));
+        let bottom = lerp(u, gradient(hash(0, 0), fx, fy), gradient(hash(1, 0), fx - 1.0, fy));
+        let top = lerp(u, gradient(hash(0, 1), fx, fy - 1.0), gradient(hash(1, 1), fx - 1.0, fy - 1.0));
+        lerp(v, bottom, top)
+    }
+}
+
+// Smoothstep of the quintic kind, so the noise has no visible creases at cell borders
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+// Dot product of the offset with one of 8 gradient directions picked by the hash
+fn gradient(hash: u8, x: f64, y: f64) -> f64 {
+    match hash & 7 {
+        0 => x + y,
+        1 => x - y,
+        2 => -x + y,
+        3 => -x - y,
+        4 => x,
+        5 => -x,
+        6 => y,
+        _ => -y,
+    }
+}
@@ -0,0 +1,514 @@
+use std::{collections::{HashMap, HashSet}, fs, path::Path, str::FromStr};
+use crate::{atomic::write_atomic, color::ColorSpace, error::{HerzError, Result}};
+use kiddo::KdTree;
+use crate::text::{clean_lines, csv_records};
+
+// Converts a hex color string (e.g., "#RRGGBB", or the "#RGB" shorthand) to an RGB tuple
+pub fn hex_to_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+    parse_hex(hex).ok()
+}
+
+// Like `hex_to_rgb`, but says what is wrong with a rejected color. Only 6 or 3 hex digits are colors:
+// anything longer would otherwise parse and lose its leading digits, so "#RRGGBBAA" became a shifted color
+pub fn parse_hex(hex: &str) -> std::result::Result<(u8, u8, u8), String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("{} isn't a hex color, '{}' is not a hex digit", hex, c));
+    }
+    // "#3a5" is short for "#33aa55"
+    let expanded: String;
+    let digits = match digits.len() {
+        6 => digits,
+        3 => {
+            expanded = digits.chars().flat_map(|c| [c, c]).collect();
+            &expanded
+        }
+        8 => return Err(format!("{} has 8 digits, palette colors only have alpha with --palette-alpha: use #RRGGBB", hex)),
+        n => return Err(format!("{} has {} digits, expected 6 (#RRGGBB) or 3 (#RGB)", hex, n)),
+    };
+    let c = u32::from_str_radix(digits, 16).expect("checked to be 6 hex digits");
+    Ok(((c >> 16) as u8, (c >> 8) as u8, c as u8))
+}
+
+// Rounds a palette point to the nearest RGB color, clamped to 0-255 so points that aren't
+// whole numbers (or fall slightly outside the cube) still map to the closest real color
+fn rgb_from_point(point: &[f64; 3]) -> (u8, u8, u8) {
+    let channel = |v: f64| v.round().clamp(0.0, 255.0) as u8;
+    (channel(point[0]), channel(point[1]), channel(point[2]))
+}
+
+// Parses an entry's color: with `alpha`, 8-digit "#RRGGBBAA" colors give their alpha as well,
+// otherwise only what `parse_hex` accepts. 6 and 3 digit colors have no alpha
+fn parse_color(hex: &str, alpha: bool) -> std::result::Result<([u8; 3], Option<u8>), String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if alpha && digits.len() == 8 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        let c = u32::from_str_radix(digits, 16).expect("checked to be 8 hex digits");
+        return Ok(([(c >> 24) as u8, (c >> 16) as u8, (c >> 8) as u8], Some(c as u8)));
+    }
+    parse_hex(hex).map(|(r, g, b)| ([r, g, b], None))
+}
+
+// Remembers the line of every id of a palette file, returning the earlier line when `id` was already
+// listed. A '!' doesn't make an id different, the game only knows the id
+fn earlier_line(lines: &mut HashMap<String, usize>, id: &str, line: usize) -> Option<usize> {
+    let id = id.strip_prefix('!').unwrap_or(id);
+    match lines.get(id) {
+        Some(&earlier) => Some(earlier),
+        None => {
+            lines.insert(id.to_string(), line);
+            None
+        }
+    }
+}
+
+// First bytes of a compiled palette file, which text palettes can never start with
+const COMPILED_MAGIC: &[u8] = b"HZPAL\x00\x01";
+
+// Header names of the id and color columns of a .csv palette unless told otherwise
+pub const DEFAULT_CSV_COLUMNS: (&str, &str) = ("id", "hex");
+
+// A palette entry dropped by `Palette::merge_close`, with the id it was merged into and their squared distance
+pub struct MergedEntry {
+    pub id: String,
+    pub into: String,
+    pub distance: f64,
+}
+
+// Tile ids and their colors, plus the lookup structures used to match colors against them
+pub struct Palette {
+    pub ids: Vec<String>,
+    pub points: Vec<[f64; 3]>,
+    // The "[section]" each id was listed under in its palette file, if any. Only text palettes have sections
+    pub sections: Vec<Option<String>>,
+    // Ids written with a leading '!' in the palette file: matched only on their exact color, never as
+    // the nearest color of another one, like ids passed to --lock-ids
+    pub exact_only: Vec<bool>,
+    // Why lines of the palette text were skipped, as "line N: reason", for the caller to report
+    pub warnings: Vec<String>,
+    // The alpha of entries written as "#RRGGBBAA", read only by the alpha-aware loaders (--palette-alpha).
+    // None for colors without one
+    pub alphas: Vec<Option<u8>>,
+    // Exact-match lookup table covering every palette color.
+    // The first entry wins when several ids share a color
+    pub(crate) exact: HashMap<(u8, u8, u8), usize>,
+    // kd-tree over the palette (3-dimensional points, storing u64 indices)
+    pub(crate) kdtree: KdTree<f64, 3>,
+}
+
+impl Palette {
+    // Builds a palette from ids and their RGB colors, such as colors picked in a GUI.
+    // Fails when there are no entries or an id appears twice
+    pub fn from_entries(entries: Vec<(String, [u8; 3])>) -> Result<Palette> {
+        if entries.is_empty() {
+            return Err(HerzError::Palette("Palette has no entries".to_string()));
+        }
+        let mut seen = HashSet::new();
+        if let Some((id, _)) = entries.iter().find(|(id, _)| !seen.insert(id.as_str())) {
+            return Err(HerzError::Palette(format!("Duplicate palette id: {}", id)));
+        }
+        let (ids, points) = entries.into_iter()
+            .map(|(id, [r, g, b])| (id, [r as f64, g as f64, b as f64]))
+            .unzip();
+        Ok(Palette::new(ids, points))
+    }
+
+    // Builds the lookup structures for parallel lists of ids and RGB points
+    fn new(ids: Vec<String>, points: Vec<[f64; 3]>) -> Palette {
+        let mut exact = HashMap::new();
+        let mut kdtree: KdTree<f64, 3> = KdTree::new();
+        for (i, point) in points.iter().enumerate() {
+            exact.entry(rgb_from_point(point)).or_insert(i);
+            kdtree.add(point, i as u64);
+        }
+        let (sections, exact_only, alphas) = (vec![None; ids.len()], vec![false; ids.len()], vec![None; ids.len()]);
+        Palette { ids, points, sections, exact_only, warnings: Vec::new(), alphas, exact, kdtree }
+    }
+
+    // Loads a palette file where each line is "id #RRGGBB", a compiled palette written by `save_compiled`,
+    // or a .csv file with "id" and "hex" columns
+    pub fn load(path: &str) -> Result<Palette> {
+        Palette::load_with_columns(path, DEFAULT_CSV_COLUMNS)
+    }
+
+    // Like `load`, with the header names of the id and color columns of a .csv palette
+    pub fn load_with_columns(path: &str, csv_columns: (&str, &str)) -> Result<Palette> {
+        Palette::load_file(path, csv_columns, false)
+    }
+
+    // Like `load_with_columns`, also accepting "#RRGGBBAA" colors in text and .csv palettes, their alpha
+    // going to `alphas`. Compiled palettes have no alpha
+    pub fn load_with_alpha(path: &str, csv_columns: (&str, &str)) -> Result<Palette> {
+        Palette::load_file(path, csv_columns, true)
+    }
+
+    fn load_file(path: &str, csv_columns: (&str, &str), alpha: bool) -> Result<Palette> {
+        let data = fs::read(path).map_err(HerzError::io("read palette file", path))?;
+        Palette::load_data(path, data, csv_columns, alpha)
+    }
+
+    // Like `load_with_columns` (or `load_with_alpha` with `alpha`) for a palette already read into memory,
+    // ex: downloaded. `path` tells a .csv palette apart and names the palette in errors
+    pub fn load_data(path: &str, data: Vec<u8>, csv_columns: (&str, &str), alpha: bool) -> Result<Palette> {
+        let is_csv = Path::new(path).extension().is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+        let palette = match data.strip_prefix(COMPILED_MAGIC) {
+            Some(body) => Palette::from_compiled(body),
+            None => {
+                let text = String::from_utf8(data).map_err(HerzError::decode("palette", path))?;
+                if is_csv { Palette::csv(&text, csv_columns, alpha) } else { Palette::text(&text, alpha) }
+            }
+        };
+        palette.map_err(|e| match e {
+            HerzError::Palette(reason) => HerzError::Palette(format!("{}: {}", path, reason)),
+            e => e,
+        })
+    }
+
+    // Writes the palette in the compiled binary format: the magic bytes, a little-endian u32 entry count,
+    // then per entry a little-endian u16 id length, the id's UTF-8 bytes and its RGB color. Exact-only ids
+    // keep their '!' prefix, sections aren't kept
+    pub fn save_compiled(&self, path: &str) -> Result<()> {
+        let mut data = COMPILED_MAGIC.to_vec();
+        data.extend((self.len() as u32).to_le_bytes());
+        for (i, id) in self.ids.iter().enumerate() {
+            let id = if self.exact_only[i] { format!("!{}", id) } else { id.clone() };
+            let len = u16::try_from(id.len()).map_err(|_| HerzError::Palette(format!("Palette id is too long: {}", id)))?;
+            let (r, g, b) = self.color(i);
+            data.extend(len.to_le_bytes());
+            data.extend(id.as_bytes());
+            data.extend([r, g, b]);
+        }
+        write_atomic(path, |temp| fs::write(temp, &data).map_err(HerzError::io("write compiled palette", path)))
+    }
+
+    // Reads the entries of a compiled palette, after its magic bytes
+    fn from_compiled(mut body: &[u8]) -> Result<Palette> {
+        let truncated = || HerzError::Palette("Compiled palette is truncated".to_string());
+        let mut take = |n: usize| -> Result<&[u8]> {
+            let (head, rest) = body.split_at_checked(n).ok_or_else(truncated)?;
+            body = rest;
+            Ok(head)
+        };
+        let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = u16::from_le_bytes(take(2)?.try_into().unwrap());
+            let id = std::str::from_utf8(take(len as usize)?)
+                .map_err(|_| HerzError::Palette("Compiled palette has an id that isn't UTF-8".to_string()))?
+                .to_string();
+            entries.push((id, take(3)?.try_into().unwrap()));
+        }
+        if !body.is_empty() {
+            return Err(HerzError::Palette(format!("Compiled palette has {} bytes after its last entry", body.len())));
+        }
+        Palette::from_marked_entries(entries)
+    }
+
+    // Parses CSV text whose header row names the columns: the id and color columns are found by name
+    // (case-insensitively), every other column is ignored. Rows like the lines of a text palette,
+    // with a warning for every skipped one (including a repeated id)
+    pub fn from_csv(content: &str, csv_columns: (&str, &str)) -> Result<Palette> {
+        Palette::csv(content, csv_columns, false)
+    }
+
+    fn csv(content: &str, (id_column, hex_column): (&str, &str), alpha: bool) -> Result<Palette> {
+        let mut records = csv_records(content);
+        let (_, header) = records.next().ok_or_else(|| HerzError::Palette("CSV palette is empty".to_string()))?;
+        let column = |name: &str| header.iter().position(|h| h.eq_ignore_ascii_case(name)).ok_or_else(|| {
+            HerzError::Palette(format!("CSV palette has no \"{}\" column, its header is: {}", name, header.join(", ")))
+        });
+        let (id_at, hex_at) = (column(id_column)?, column(hex_column)?);
+        let mut entries = Vec::new();
+        let mut warnings = Vec::new();
+        let mut alphas = Vec::new();
+        let mut id_lines = HashMap::new();
+        for (line, fields) in records {
+            let field = |at: usize| fields.get(at).map_or("", String::as_str);
+            let entry = match (field(id_at), field(hex_at)) {
+                (id, _) if id.trim_start_matches('!').is_empty() => Err(format!("no value in the {} column", id_column)),
+                (_, "") => Err(format!("no value in the {} column", hex_column)),
+                (id, hex) => parse_color(hex, alpha).map(|(rgb, a)| (id.to_string(), rgb, a)),
+            };
+            let entry = entry.and_then(|(id, rgb, a)| match earlier_line(&mut id_lines, &id, line) {
+                Some(earlier) => Err(format!("{} is already on line {}", id, earlier)),
+                None => Ok((id, rgb, a)),
+            });
+            match entry {
+                Ok((id, rgb, a)) => {
+                    entries.push((id, rgb));
+                    alphas.push(a);
+                }
+                Err(reason) => warnings.push(format!("line {}: {}", line, reason)),
+            }
+        }
+        if entries.is_empty() && let Some(first) = warnings.first() {
+            return Err(HerzError::Palette(format!("Palette has no entries, {}", first)));
+        }
+        let mut palette = Palette::from_marked_entries(entries)?;
+        palette.warnings = warnings;
+        palette.alphas = alphas;
+        Ok(palette)
+    }
+
+    // Like `from_entries`, but an id with a leading '!' is stripped of it and marked exact-only
+    pub fn from_marked_entries(entries: Vec<(String, [u8; 3])>) -> Result<Palette> {
+        let (entries, exact_only): (Vec<_>, Vec<bool>) = entries.into_iter().map(|(id, rgb)| match id.strip_prefix('!') {
+            Some(id) => ((id.to_string(), rgb), true),
+            None => ((id, rgb), false),
+        }).unzip();
+        let mut palette = Palette::from_entries(entries)?;
+        palette.exact_only = exact_only;
+        Ok(palette)
+    }
+
+    // Collapses entries within `threshold` squared distance (in `space`) of an earlier kept entry into it, so
+    // near-duplicate colors don't split borderline pixels between two ids. The first id in file order is
+    // kept with its section and exact-only mark. Exact-only entries are only merged with each other, a
+    // matchable color never disappears into one. Returns the merged palette and every dropped entry
+    pub fn merge_close(self, threshold: f64, space: ColorSpace) -> Result<(Palette, Vec<MergedEntry>)> {
+        let converted: Vec<[f64; 3]> = self.points.iter().map(|&point| space.convert(point)).collect();
+        let mut kept: Vec<usize> = Vec::new();
+        let mut merged = Vec::new();
+        for i in 0..self.len() {
+            let [x, y, z] = converted[i];
+            let close = kept.iter().filter(|&&k| self.exact_only[k] == self.exact_only[i]).map(|&k| {
+                let [a, b, c] = converted[k];
+                (k, (a - x).powi(2) + (b - y).powi(2) + (c - z).powi(2))
+            }).find(|&(_, distance)| distance <= threshold);
+            match close {
+                Some((k, distance)) => merged.push(MergedEntry { id: self.ids[i].clone(), into: self.ids[k].clone(), distance }),
+                None => kept.push(i),
+            }
+        }
+        if merged.is_empty() {
+            return Ok((self, merged));
+        }
+        let entries = kept.iter().map(|&k| {
+            let (r, g, b) = self.color(k);
+            (self.ids[k].clone(), [r, g, b])
+        }).collect();
+        let mut palette = Palette::from_entries(entries)?;
+        palette.sections = kept.iter().map(|&k| self.sections[k].clone()).collect();
+        palette.exact_only = kept.iter().map(|&k| self.exact_only[k]).collect();
+        palette.alphas = kept.iter().map(|&k| self.alphas[k]).collect();
+        palette.warnings = self.warnings;
+        Ok((palette, merged))
+    }
+
+    // Adds the entries of `other` after this palette's, in their order and with their sections, exact-only
+    // marks and alphas. Ids this palette already has keep their entry here, the skipped ones are returned.
+    // The exact color lookup keeps the first entry of a color, so a color both have stays this palette's id
+    pub fn append(self, other: Palette) -> Result<(Palette, Vec<String>)> {
+        let known: HashSet<&String> = self.ids.iter().collect();
+        let (added, skipped): (Vec<usize>, Vec<usize>) = (0..other.len()).partition(|&i| !known.contains(&other.ids[i]));
+        let skipped = skipped.into_iter().map(|i| other.ids[i].clone()).collect();
+        if added.is_empty() {
+            return Ok((self, skipped));
+        }
+        let sources = (0..self.len()).map(|i| (&self, i)).chain(added.iter().map(|&i| (&other, i)));
+        let (mut entries, mut sections, mut exact_only, mut alphas) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for (palette, i) in sources {
+            let (r, g, b) = palette.color(i);
+            entries.push((palette.ids[i].clone(), [r, g, b]));
+            sections.push(palette.sections[i].clone());
+            exact_only.push(palette.exact_only[i]);
+            alphas.push(palette.alphas[i]);
+        }
+        let mut palette = Palette::from_entries(entries)?;
+        (palette.sections, palette.exact_only, palette.alphas) = (sections, exact_only, alphas);
+        palette.warnings = self.warnings.into_iter().chain(other.warnings).collect();
+        Ok((palette, skipped))
+    }
+
+    // Writes the given entries, in the order given, as palette text that parses back into the same entries:
+    // "[section]" lines where the section changes, exact-only ids with their '!' and alphas as "#RRGGBBAA"
+    pub fn to_text(&self, entries: &[usize]) -> String {
+        let mut text = String::new();
+        let mut section = None;
+        for &i in entries {
+            if self.sections[i].is_some() && self.sections[i] != section {
+                section = self.sections[i].clone();
+                text.push_str(&format!("[{}]\n", section.as_deref().unwrap_or_default()));
+            }
+            let (r, g, b) = self.color(i);
+            let mark = if self.exact_only[i] { "!" } else { "" };
+            let alpha = self.alphas[i].map(|a| format!("{:02X}", a)).unwrap_or_default();
+            text.push_str(&format!("{}{} #{:02X}{:02X}{:02X}{}\n", mark, self.ids[i], r, g, b, alpha));
+        }
+        text
+    }
+
+    // The RGB color written back for a palette entry
+    pub fn color(&self, idx: usize) -> (u8, u8, u8) {
+        rgb_from_point(&self.points[idx])
+    }
+
+    // The palette entry with exactly this color, if any
+    pub fn exact_index(&self, color: (u8, u8, u8)) -> Option<usize> {
+        self.exact.get(&color).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+// Parses palette text where each line is "id #RRGGBB", or "!id #RRGGBB" for an exact-only id. Blank lines and
+// "# comment" lines are skipped, a "[section]" line names the section of the entries below it, other lines
+// that don't fit and later lines of an id already listed are skipped with a warning
+impl FromStr for Palette {
+    type Err = HerzError;
+
+    fn from_str(content: &str) -> Result<Palette> {
+        Palette::text(content, false)
+    }
+}
+
+impl Palette {
+    // The text palette parser behind `FromStr`, with `alpha` also accepting "#RRGGBBAA" colors
+    fn text(content: &str, alpha: bool) -> Result<Palette> {
+        let mut section = None;
+        let mut alphas = Vec::new();
+        let mut entries = Vec::new();
+        let mut sections = Vec::new();
+        let mut warnings = Vec::new();
+        let mut id_lines = HashMap::new();
+        for (number, line) in clean_lines(content).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+                continue;
+            }
+            let entry = match line.split_once(' ') {
+                Some((id, _)) if id.trim_start_matches('!').is_empty() => Err("no id before the color".to_string()),
+                Some((id, hex)) => parse_color(hex.trim(), alpha).map(|(rgb, a)| (id.to_string(), rgb, a)),
+                None => Err("expected \"id #RRGGBB\"".to_string()),
+            };
+            // A file listing an id twice keeps the first, what the game would pick
+            let entry = entry.and_then(|(id, rgb, a)| match earlier_line(&mut id_lines, &id, number + 1) {
+                Some(earlier) => Err(format!("{} is already on line {}", id, earlier)),
+                None => Ok((id, rgb, a)),
+            });
+            match entry {
+                Ok((id, rgb, a)) => {
+                    entries.push((id, rgb));
+                    sections.push(section.clone());
+                    alphas.push(a);
+                }
+                Err(reason) => warnings.push(format!("line {}: {}", number + 1, reason)),
+            }
+        }
+        // A file of nothing but bad lines says why instead of just having no entries
+        if entries.is_empty() && let Some(first) = warnings.first() {
+            return Err(HerzError::Palette(format!("Palette has no entries, {}", first)));
+        }
+        let mut palette = Palette::from_marked_entries(entries)?;
+        palette.sections = sections;
+        palette.warnings = warnings;
+        palette.alphas = alphas;
+        Ok(palette)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A palette read from its file bytes, the way `load` reads it
+    fn load_text(content: &str) -> Palette {
+        Palette::load_data("test.txt", content.as_bytes().to_vec(), DEFAULT_CSV_COLUMNS, false).unwrap()
+    }
+
+    #[test]
+    fn crlf_palette_with_bom_loads_clean_ids() {
+        let palette = load_text("\u{feff}grass #00FF00\r\nwater #0000FF\r\n\r\nsand #C2B280\r\n");
+        assert_eq!(palette.ids, ["grass", "water", "sand"]);
+        assert_eq!(palette.color(0), (0, 255, 0));
+        assert_eq!(palette.color(2), (0xC2, 0xB2, 0x80));
+        assert!(palette.warnings.is_empty(), "{:?}", palette.warnings);
+    }
+
+    #[test]
+    fn repeated_id_in_a_file_keeps_the_first_with_a_warning() {
+        let palette = load_text("sand #F7E898\nwater #3370CC\n!sand #000000\n");
+        assert_eq!(palette.ids, ["sand", "water"]);
+        assert_eq!(palette.color(0), (0xF7, 0xE8, 0x98));
+        assert_eq!(palette.warnings, ["line 3: !sand is already on line 1"]);
+
+        let csv = Palette::from_csv("id,hex\nsand,F7E898\nsand,000000\n", DEFAULT_CSV_COLUMNS).unwrap();
+        assert_eq!(csv.ids, ["sand"]);
+        assert_eq!(csv.warnings, ["line 3: sand is already on line 2"]);
+    }
+
+    #[test]
+    fn from_entries_rejects_a_repeated_id() {
+        let entries = vec![("sand".to_string(), [0xF7, 0xE8, 0x98]), ("sand".to_string(), [0, 0, 0])];
+        assert!(matches!(Palette::from_entries(entries), Err(HerzError::Palette(_))));
+    }
+
+    #[test]
+    fn fractional_points_round_to_the_nearest_color() {
+        let points = vec![[12.6, 0.4, 254.5], [-3.0, 255.7, 127.49]];
+        let palette = Palette::new(vec!["a".to_string(), "b".to_string()], points);
+        assert_eq!(palette.color(0), (13, 0, 255));
+        assert_eq!(palette.color(1), (0, 255, 127));
+        // The exact-match table holds the same colors that are written back
+        assert_eq!(palette.exact_index((13, 0, 255)), Some(0));
+        assert_eq!(palette.exact_index((0, 255, 127)), Some(1));
+        assert_eq!(palette.exact_index((12, 0, 254)), None);
+    }
+
+    #[test]
+    fn compiled_palette_round_trips() {
+        let palette = load_text("[water]\ndeep_ocean #3370CC\n!ice #DDEEFF\n[land]\nsand #F7E898\nsoil_low:grass_low #54CC3A\n");
+        let path = std::env::temp_dir().join(format!("herzmapper-test-{}.hzpal", std::process::id()));
+        let path = path.to_str().unwrap();
+        palette.save_compiled(path).unwrap();
+        let data = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(data.starts_with(COMPILED_MAGIC));
+
+        let loaded = Palette::load_data(path, data.clone(), DEFAULT_CSV_COLUMNS, false).unwrap();
+        assert_eq!(loaded.ids, palette.ids);
+        assert_eq!(loaded.points, palette.points);
+        assert_eq!(loaded.exact_only, [false, true, false, false]);
+        assert_eq!(loaded.exact_index((0xF7, 0xE8, 0x98)), Some(2));
+        // Sections aren't compiled
+        assert!(loaded.sections.iter().all(Option::is_none));
+
+        assert!(Palette::load_data(path, data[..data.len() - 1].to_vec(), DEFAULT_CSV_COLUMNS, false).is_err());
+    }
+
+    #[test]
+    fn comments_and_sections_between_entries() {
+        let palette = load_text("# Water\ndeep_ocean #3370CC\n\n[ land ]\n  # sand and grass\nsand #F7E898\n#F7E898\nsoil_low:grass_low #54CC3A\n[]\nstone #808080\n");
+        assert_eq!(palette.ids, ["deep_ocean", "sand", "soil_low:grass_low", "stone"]);
+        assert_eq!(palette.sections, [None, Some("land".to_string()), Some("land".to_string()), None]);
+        assert!(palette.warnings.is_empty(), "{:?}", palette.warnings);
+    }
+
+    #[test]
+    fn hex_colors_need_3_or_6_digits() {
+        assert_eq!(parse_hex("#3A5"), Ok((0x33, 0xAA, 0x55)));
+        assert_eq!(parse_hex("3370CC"), Ok((0x33, 0x70, 0xCC)));
+        for hex in ["#3370C", "#3370CCF", "#3370CCFF"] {
+            assert!(parse_hex(hex).is_err(), "{}", hex);
+        }
+        assert!(parse_hex("#3370CCFF").unwrap_err().contains("--palette-alpha"));
+        assert!(parse_hex("+3370CC").is_err());
+
+        // A palette file warns about each bad line and keeps the rest
+        let palette = load_text("five #3370C\nsix #3370CC\nseven #3370CCF\neight #3370CCFF\n");
+        assert_eq!(palette.ids, ["six"]);
+        assert_eq!(palette.warnings.len(), 3);
+        assert!(palette.warnings[1].starts_with("line 3: #3370CCF has 7 digits"), "{}", palette.warnings[1]);
+        // With --palette-alpha 8 digits are a color and its alpha
+        let palette = Palette::load_data("test.txt", b"eight #3370CC80".to_vec(), DEFAULT_CSV_COLUMNS, true).unwrap();
+        assert_eq!((palette.color(0), palette.alphas[0]), ((0x33, 0x70, 0xCC), Some(0x80)));
+    }
+}
@@ -0,0 +1,39 @@
+// How well the palette fits a source image: the distance of every pixel to the color it was matched to
+use image::{Rgb, RgbImage};
+use rayon::prelude::*;
+use crate::{color::ColorSpace, palette::Palette};
+
+// Squared distance from every pixel to the palette color of its grid entry, in `space` like the nearest
+// match search, row-major from the top-left. Exact matches are 0
+pub fn matched_distances(img: &RgbImage, grid: &[usize], palette: &Palette, space: ColorSpace) -> Vec<f64> {
+    img.as_raw().par_chunks(3).zip(grid.par_iter()).map(|(p, &idx)| {
+        let [a, b, c] = space.convert(palette.points[idx]);
+        let [x, y, z] = space.convert([p[0] as f64, p[1] as f64, p[2] as f64]);
+        (a - x).powi(2) + (b - y).powi(2) + (c - z).powi(2)
+    }).collect()
+}
+
+// Shades every tile from green (exact) over yellow to red (the farthest match of the image), by its
+// distance relative to the largest one so the worst areas always stand out
+pub fn heatmap(distances: &[f64], width: u32, height: u32) -> RgbImage {
+    let farthest = distances.iter().copied().fold(0.0, f64::max).sqrt();
+    RgbImage::from_fn(width, height, |x, y| {
+        let distance = distances[(y * width + x) as usize].sqrt();
+        let t = if farthest > 0.0 { distance / farthest } else { 0.0 };
+        let red = (t * 2.0).min(1.0);
+        let green = (2.0 - t * 2.0).min(1.0);
+        Rgb([(red * 255.0).round() as u8, (green * 255.0).round() as u8, 0])
+    })
+}
+
+// Per pixel RGB distance between two images of the same size as a gray level, from black (identical) to white (black
+// against white), on a fixed scale so deltas of different runs compare. Any change shows as at least 1
+pub fn delta(source: &RgbImage, mapped: &RgbImage) -> RgbImage {
+    let farthest = (3.0f64 * 255.0 * 255.0).sqrt();
+    RgbImage::from_fn(source.width(), source.height(), |x, y| {
+        let (a, b) = (source.get_pixel(x, y).0, mapped.get_pixel(x, y).0);
+        let distance = a.iter().zip(b).map(|(&a, b)| (a as f64 - b as f64).powi(2)).sum::<f64>().sqrt();
+        let level = if distance > 0.0 { (distance / farthest * 255.0).round().max(1.0) } else { 0.0 };
+        Rgb([level as u8; 3])
+    })
+}
@@ -0,0 +1,289 @@
+use std::{borrow::Cow, collections::{HashMap, HashSet}, sync::Arc};
+use crate::error::{HerzError, Result};
+use image::RgbImage;
+use kiddo::{KdTree, SquaredEuclidean};
+use rayon::prelude::*;
+use crate::{color::ColorSpace, lut::Lut, palette::Palette};
+
+// Options controlling how source colors are matched to the palette
+#[derive(Clone, Default)]
+pub struct QuantizeOptions {
+    // Palette ids that are never chosen as a nearest match, only on exact color matches
+    pub locked_ids: HashSet<String>,
+    // Color space the nearest palette color is measured in
+    pub color_space: ColorSpace,
+    // Number of nearest palette colors each pixel picks from at random, 0 or 1 always takes the nearest
+    pub variation_k: usize,
+    // Seed for the random pick, the same seed always gives the same tiles
+    pub seed: u64,
+    // Precomputed table answering nearest matches instead of the kd-tree
+    pub lut: Option<Arc<Lut>>,
+    // Pulls colors outside the palette's range toward its centroid before searching, see `Gamut`
+    pub clamp_gamut: bool,
+}
+
+// The range spanned by the matchable palette colors in the match color space: the bounding box of
+// their points and their centroid, which always lies inside the box
+struct Gamut {
+    centroid: [f64; 3],
+    min: [f64; 3],
+    max: [f64; 3],
+}
+
+impl Gamut {
+    fn new(palette: &Palette, opts: &QuantizeOptions) -> Option<Gamut> {
+        let points: Vec<[f64; 3]> = palette.points.iter().enumerate()
+            .filter(|(i, _)| matchable(palette, opts, *i))
+            .map(|(_, point)| opts.color_space.convert(*point))
+            .collect();
+        if points.is_empty() {
+            return None;
+        }
+        let mut gamut = Gamut { centroid: [0.0; 3], min: [f64::INFINITY; 3], max: [f64::NEG_INFINITY; 3] };
+        for point in &points {
+            for (axis, &value) in point.iter().enumerate() {
+                gamut.centroid[axis] += value / points.len() as f64;
+                gamut.min[axis] = gamut.min[axis].min(value);
+                gamut.max[axis] = gamut.max[axis].max(value);
+            }
+        }
+        Some(gamut)
+    }
+
+    // Moves a point outside the box along the line toward the centroid until it reaches the box, so an
+    // extreme color keeps its direction from the palette's center instead of snapping to whichever
+    // palette color happens to sit on the nearest edge. Points inside the box are unchanged
+    fn clamp(&self, point: [f64; 3]) -> [f64; 3] {
+        let mut t: f64 = 1.0;
+        for (axis, &value) in point.iter().enumerate() {
+            let offset = value - self.centroid[axis];
+            let limit = if offset > 0.0 { self.max[axis] } else { self.min[axis] } - self.centroid[axis];
+            if offset.abs() > limit.abs() {
+                t = t.min(limit / offset);
+            }
+        }
+        [0, 1, 2].map(|axis| self.centroid[axis] + (point[axis] - self.centroid[axis]) * t)
+    }
+}
+
+// The point a color is searched at: converted into the match color space, then clamped with --clamp-gamut
+fn query_point(col: (u8, u8, u8), space: ColorSpace, gamut: Option<&Gamut>) -> [f64; 3] {
+    let point = space.convert([col.0 as f64, col.1 as f64, col.2 as f64]);
+    gamut.map_or(point, |gamut| gamut.clamp(point))
+}
+
+// Whether a palette entry can be the nearest match of another color: it is neither locked
+// with --lock-ids nor marked exact-only in the palette file
+fn matchable(palette: &Palette, opts: &QuantizeOptions, i: usize) -> bool {
+    !palette.exact_only[i] && !opts.locked_ids.contains(&palette.ids[i])
+}
+
+// Builds the kd-tree nearest matches are searched in. The palette's own tree is in RGB with
+// every id, anything else needs a tree of its own
+fn match_tree<'a>(palette: &'a Palette, opts: &QuantizeOptions) -> Cow<'a, KdTree<f64, 3>> {
+    let space = opts.color_space;
+    if opts.locked_ids.is_empty() && !palette.exact_only.contains(&true) && space == ColorSpace::Rgb {
+        return Cow::Borrowed(&palette.kdtree);
+    }
+    let mut kdtree: KdTree<f64, 3> = KdTree::new();
+    for (i, point) in palette.points.iter().enumerate() {
+        if matchable(palette, opts, i) {
+            kdtree.add(&space.convert(*point), i as u64);
+        }
+    }
+    Cow::Owned(kdtree)
+}
+
+// Maps every unique color of the image to a palette index: its exact palette match if there is one,
+// otherwise its nearest palette color. Locked ids stay in the exact-match table but are left out of the kd-tree
+pub fn match_colors(img: &RgbImage, palette: &Palette, opts: &QuantizeOptions) -> Result<HashMap<(u8, u8, u8), usize>> {
+    // Extract unique colors from the image
+    let unique = unique_colors(img);
+
+    // A lookup table already holds the nearest match of every sample of the RGB cube
+    if let Some(lut) = &opts.lut {
+        return Ok(unique.into_par_iter().map(|col| {
+            (col, palette.exact.get(&col).copied().unwrap_or_else(|| lut.lookup(col)))
+        }).collect());
+    }
+
+    let space = opts.color_space;
+    let kdtree = match_tree(palette, opts);
+    ensure_candidates(&kdtree, palette, &unique)?;
+    let gamut = opts.clamp_gamut.then(|| Gamut::new(palette, opts)).flatten();
+
+    // In parallel, map each unique color to its palette index
+    Ok(unique.into_par_iter().map(|col| {
+        let idx = palette.exact.get(&col).copied().unwrap_or_else(|| {
+            let query = query_point(col, space, gamut.as_ref());
+            let nn: kiddo::NearestNeighbour<f64, u64> = kdtree.nearest_one::<SquaredEuclidean>(&query);
+            nn.item as usize
+        });
+        (col, idx)
+    }).collect())
+}
+
+// An empty kd-tree has no nearest neighbor to give, so colors without an exact match can't be matched
+// once every palette id is locked
+fn ensure_candidates(kdtree: &KdTree<f64, 3>, palette: &Palette, unique: &HashSet<(u8, u8, u8)>) -> Result<()> {
+    if kdtree.size() == 0
+        && let Some((r, g, b)) = unique.iter().find(|col| !palette.exact.contains_key(col))
+    {
+        return Err(HerzError::Matching(format!(
+            "No candidate tiles remain for matching: #{:02X}{:02X}{:02X} has no exact palette match and all {} palette ids are locked",
+            r, g, b, palette.len()
+        )));
+    }
+    Ok(())
+}
+
+// The distinct colors of an image, the set every matching works on
+pub fn unique_colors(img: &RgbImage) -> HashSet<(u8, u8, u8)> {
+    img.pixels().map(|p| (p[0], p[1], p[2])).collect()
+}
+
+// Sum of the distances from each color to its nearest palette color, in the options' color space and
+// without locked ids, the same way a conversion would match them. Lower means the palette fits better
+pub fn match_distance(unique: &HashSet<(u8, u8, u8)>, palette: &Palette, opts: &QuantizeOptions) -> Result<f64> {
+    let space = opts.color_space;
+    let kdtree = match_tree(palette, opts);
+    ensure_candidates(&kdtree, palette, unique)?;
+    let gamut = opts.clamp_gamut.then(|| Gamut::new(palette, opts)).flatten();
+    Ok(unique.par_iter().map(|&col| {
+        if palette.exact.contains_key(&col) {
+            return 0.0;
+        }
+        let query = query_point(col, space, gamut.as_ref());
+        let nn: kiddo::NearestNeighbour<f64, u64> = kdtree.nearest_one::<SquaredEuclidean>(&query);
+        // With clamping the distance is measured from the color itself, not from its clamped point
+        let point = palette.points[nn.item as usize];
+        let [a, b, c] = space.convert(point);
+        let [x, y, z] = space.convert([col.0 as f64, col.1 as f64, col.2 as f64]);
+        ((a - x).powi(2) + (b - y).powi(2) + (c - z).powi(2)).sqrt()
+    }).sum())
+}
+
+// Matches every pixel to a palette index, row-major from the top-left. With a variation_k above 1,
+// colors without an exact match pick at random among their k nearest palette colors, weighted
+// by inverse distance, so uniform areas get a natural mottling. Exact matches are never varied
+pub fn match_pixels(img: &RgbImage, palette: &Palette, opts: &QuantizeOptions) -> Result<Vec<usize>> {
+    // With a lookup table every pixel is answered directly, skipping the unique color pass
+    if let Some(lut) = &opts.lut {
+        return Ok(img.as_raw().par_chunks(3).map(|p| {
+            let col = (p[0], p[1], p[2]);
+            palette.exact.get(&col).copied().unwrap_or_else(|| lut.lookup(col))
+        }).collect());
+    }
+    if opts.variation_k <= 1 {
+        let matches = match_colors(img, palette, opts)?;
+        return Ok(img.pixels().map(|p| matches[&(p[0], p[1], p[2])]).collect());
+    }
+
+    let space = opts.color_space;
+    let kdtree = match_tree(palette, opts);
+
+    // Candidate palette indexes for each unique color with their cumulative weights
+    let unique = unique_colors(img);
+    ensure_candidates(&kdtree, palette, &unique)?;
+    let gamut = opts.clamp_gamut.then(|| Gamut::new(palette, opts)).flatten();
+    let candidates: HashMap<(u8, u8, u8), Vec<(usize, f64)>> = unique.into_par_iter().map(|col| {
+        if let Some(&idx) = palette.exact.get(&col) {
+            return (col, vec![(idx, 1.0)]);
+        }
+        let query = query_point(col, space, gamut.as_ref());
+        let mut total = 0.0;
+        let weighted = kdtree.nearest_n::<SquaredEuclidean>(&query, opts.variation_k).into_iter().map(|nn| {
+            total += 1.0 / nn.distance.sqrt().max(1e-6);
+            (nn.item as usize, total)
+        }).collect();
+        (col, weighted)
+    }).collect();
+
+    Ok(img.as_raw().par_chunks(3).enumerate().map(|(i, p)| {
+        let weighted = &candidates[&(p[0], p[1], p[2])];
+        let total = weighted[weighted.len() - 1].1;
+        let roll = unit_random(opts.seed, i as u64) * total;
+        weighted.iter().find(|(_, cumulative)| roll < *cumulative).unwrap_or(&weighted[weighted.len() - 1]).0
+    }).collect())
+}
+
+// Deterministic random number in [0, 1) for a pixel (or any other index), from a splitmix64 hash of the seed and index
+pub(crate) fn unit_random(seed: u64, index: u64) -> f64 {
+    let mut z = seed ^ index.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Paints each pixel with the color of its palette index from match_pixels in parallel
+pub fn apply_grid(img: &mut RgbImage, grid: &[usize], palette: &Palette) {
+    img.as_mut().par_chunks_mut(3).zip(grid.par_iter()).for_each(|(pixel, &idx)| {
+        let new = palette.color(idx);
+        pixel[0] = new.0;
+        pixel[1] = new.1;
+        pixel[2] = new.2;
+    });
+}
+
+// Replaces each pixel with the color of its matched palette entry in parallel
+pub fn apply_matches(img: &mut RgbImage, matches: &HashMap<(u8, u8, u8), usize>, palette: &Palette) {
+    img.as_mut().par_chunks_mut(3).for_each(|pixel| {
+        let key = (pixel[0], pixel[1], pixel[2]);
+        if let Some(&idx) = matches.get(&key) {
+            let new = palette.color(idx);
+            pixel[0] = new.0;
+            pixel[1] = new.1;
+            pixel[2] = new.2;
+        }
+    });
+}
+
+// Snaps an image to the palette, without any of the .wbox encoding
+pub fn quantize(img: &RgbImage, palette: &Palette, opts: &QuantizeOptions) -> Result<RgbImage> {
+    let grid = match_pixels(img, palette, opts)?;
+    let mut out = img.clone();
+    apply_grid(&mut out, &grid, palette);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn two_colors() -> Palette {
+        Palette::from_entries(vec![("water".to_string(), [0, 0, 255]), ("sand".to_string(), [255, 255, 0])]).unwrap()
+    }
+
+    fn locked(ids: &[&str]) -> QuantizeOptions {
+        QuantizeOptions { locked_ids: ids.iter().map(|id| id.to_string()).collect(), ..Default::default() }
+    }
+
+    #[test]
+    fn locking_every_id_fails_on_colors_without_an_exact_match() {
+        let palette = two_colors();
+        let img = RgbImage::from_fn(2, 1, |x, _| if x == 0 { Rgb([0, 0, 255]) } else { Rgb([10, 10, 200]) });
+        let all = locked(&["water", "sand"]);
+        for result in [match_pixels(&img, &palette, &all).map(drop), match_distance(&unique_colors(&img), &palette, &all).map(drop)] {
+            match result {
+                Err(HerzError::Matching(reason)) => assert!(reason.contains("#0A0AC8"), "{}", reason),
+                other => panic!("expected a matching error, got {:?}", other.err()),
+            }
+        }
+        let varied = QuantizeOptions { variation_k: 2, ..all.clone() };
+        assert!(matches!(match_pixels(&img, &palette, &varied), Err(HerzError::Matching(_))));
+
+        // Exact matches need no candidates, and one unlocked id is enough for the rest
+        let exact = RgbImage::from_pixel(2, 1, Rgb([255, 255, 0]));
+        assert_eq!(match_pixels(&exact, &palette, &all).unwrap(), [1, 1]);
+        assert_eq!(match_pixels(&img, &palette, &locked(&["water"])).unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn exact_only_palette_has_no_candidates_either() {
+        let palette: Palette = "!water #0000FF\n!sand #FFFF00".parse().unwrap();
+        let img = RgbImage::from_pixel(1, 1, Rgb([1, 2, 3]));
+        assert!(matches!(match_pixels(&img, &palette, &QuantizeOptions::default()), Err(HerzError::Matching(_))));
+    }
+}
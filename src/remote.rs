@@ -0,0 +1,49 @@
+// Inputs, palettes and templates given as http(s):// URLs, fetched into memory when built with
+// the `remote` feature. Local paths never go through here
+use anyhow::Result;
+
+// Whether an input path is an http(s) URL to fetch instead of a file to read
+pub fn is_url(path: &str) -> bool {
+    let lower = path.get(..8).unwrap_or(path).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+// Every attempt gets the whole timeout, from connecting to the last byte of the body
+#[cfg(feature = "remote")]
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+#[cfg(feature = "remote")]
+const ATTEMPTS: u32 = 3;
+
+// Downloads a URL's body, retrying timeouts, failed connections, 429 and 5xx responses with a growing
+// pause. Other HTTP errors (ex: 404) fail at once since asking again won't change them
+#[cfg(feature = "remote")]
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    use anyhow::Context;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .user_agent(concat!("herzmapper/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .context("Failed to set up the HTTP client")?;
+    let mut attempt = 1;
+    loop {
+        let result = client.get(url).send().and_then(|response| response.error_for_status()).and_then(|response| response.bytes());
+        let error = match result {
+            Ok(body) => return Ok(body.to_vec()),
+            Err(e) => e,
+        };
+        let transient = error.is_timeout() || error.is_connect() || error.is_body()
+            || error.status().is_some_and(|status| status.is_server_error() || status.as_u16() == 429);
+        if !transient || attempt == ATTEMPTS {
+            return Err(error).with_context(|| format!("Failed to fetch {} (attempt {} of {})", url, attempt, ATTEMPTS));
+        }
+        let pause = std::time::Duration::from_secs(1 << (attempt - 1));
+        eprintln!("Warning: fetching {} failed ({}), retrying in {}s", url, error, pause.as_secs());
+        std::thread::sleep(pause);
+        attempt += 1;
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    anyhow::bail!("{} is a URL, but this build can't fetch URLs: rebuild with `cargo build --release --features remote`", url)
+}
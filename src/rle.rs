@@ -0,0 +1,133 @@
+use crate::error::{HerzError, Result};
+
+// Run-length encodes a row-major grid (top row first) into the per-row tileArray/tileAmounts lists.
+// Rows are written bottom row first because the game's origin is the bottom-left tile
+pub fn rle_encode(grid: &[usize], width: usize) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    grid.chunks(width.max(1)).rev().map(encode_row).unzip()
+}
+
+// The runs of one row: the tile of each run and its length
+fn encode_row(row: &[usize]) -> (Vec<usize>, Vec<usize>) {
+    row.iter().fold((Vec::new(), Vec::new()), |(mut tiles, mut counts), &idx| {
+        if tiles.last() == Some(&idx) {
+            *counts.last_mut().unwrap() += 1;
+        } else {
+            tiles.push(idx);
+            counts.push(1);
+        }
+        (tiles, counts)
+    })
+}
+
+// Re-encodes only the grid rows in `rows` (top row 0, like the grid) after an edit, ex: the rows of a
+// brush stroke's bounding rectangle, replacing their lists in `tiles`/`amounts` from `rle_encode`.
+// Runs never cross rows, so the result is identical to encoding the whole grid again
+pub fn rle_update(
+    tiles: &mut [Vec<usize>], amounts: &mut [Vec<usize>], grid: &[usize], width: usize, rows: std::ops::Range<usize>,
+) -> Result<()> {
+    if width == 0 || !grid.len().is_multiple_of(width) {
+        return Err(HerzError::Template(format!("A grid of {} tiles can't be split into rows of {}", grid.len(), width)));
+    }
+    let height = grid.len() / width;
+    if tiles.len() != height || amounts.len() != height {
+        return Err(HerzError::Template(format!(
+            "tileArray has {} rows and tileAmounts {} but the grid has {}", tiles.len(), amounts.len(), height
+        )));
+    }
+    if rows.end > height {
+        return Err(HerzError::Template(format!("Rows {}..{} are outside the grid's {} rows", rows.start, rows.end, height)));
+    }
+    for y in rows {
+        // Lists are stored bottom row first
+        (tiles[height - 1 - y], amounts[height - 1 - y]) = encode_row(&grid[y * width..(y + 1) * width]);
+    }
+    Ok(())
+}
+
+// Expands per-row tileArray/tileAmounts lists back into a row-major grid (top row first)
+pub fn rle_decode(tiles: &[Vec<usize>], amounts: &[Vec<usize>], width: usize) -> Result<Vec<usize>> {
+    if tiles.len() != amounts.len() {
+        return Err(HerzError::Template(format!("tileArray has {} rows but tileAmounts has {}", tiles.len(), amounts.len())));
+    }
+    let mut grid = Vec::with_capacity(tiles.len() * width);
+    for (y, (row_tiles, row_amounts)) in tiles.iter().zip(amounts).enumerate().rev() {
+        if row_tiles.len() != row_amounts.len() {
+            return Err(HerzError::Template(format!("Row {} has {} tiles but {} amounts", y, row_tiles.len(), row_amounts.len())));
+        }
+        let start = grid.len();
+        for (&tile, &amount) in row_tiles.iter().zip(row_amounts) {
+            grid.extend(std::iter::repeat_n(tile, amount));
+        }
+        if grid.len() - start != width {
+            return Err(HerzError::Template(format!("Row {} covers {} tiles instead of {}", y, grid.len() - start, width)));
+        }
+    }
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantize::unit_random;
+    use proptest::prelude::*;
+
+    // Grids up to 64x64 built from runs of single tiles to several rows long, over one to thousands of
+    // tile ids, so both noise and flat areas are covered
+    fn grids() -> impl Strategy<Value = (Vec<usize>, usize)> {
+        (1..=64usize, 1..=64usize, prop_oneof![Just(1usize), Just(2), Just(16), Just(5000)]).prop_flat_map(|(width, height, ids)| {
+            prop::collection::vec((0..ids, 1..=width * 3), 1..=64).prop_map(move |runs| {
+                let grid = runs.iter().cycle().flat_map(|&(tile, run)| std::iter::repeat_n(tile, run)).take(width * height).collect();
+                (grid, width)
+            })
+        })
+    }
+
+    proptest! {
+        // Decoding gives back the grid, from one list per row with amounts adding up to the width, no empty
+        // runs and no two neighboring runs of the same tile
+        #[test]
+        fn encode_round_trips_with_well_formed_lists((grid, width) in grids()) {
+            let (tiles, amounts) = rle_encode(&grid, width);
+            prop_assert_eq!(tiles.len(), grid.len() / width);
+            prop_assert_eq!(amounts.len(), tiles.len());
+            for (row_tiles, row_amounts) in tiles.iter().zip(&amounts) {
+                prop_assert_eq!(row_tiles.len(), row_amounts.len());
+                prop_assert_eq!(row_amounts.iter().sum::<usize>(), width);
+                prop_assert!(!row_amounts.contains(&0));
+                prop_assert!(row_tiles.windows(2).all(|pair| pair[0] != pair[1]));
+            }
+            prop_assert_eq!(rle_decode(&tiles, &amounts, width).unwrap(), grid);
+        }
+    }
+
+    #[test]
+    fn incremental_update_matches_a_full_encode() {
+        let (width, height) = (300, 200);
+        // Runs of a few tiles, so edits split and merge them
+        let mut grid: Vec<usize> = (0..width * height).map(|i| (unit_random(1, (i / 4) as u64) * 5.0) as usize).collect();
+        let (mut tiles, mut amounts) = rle_encode(&grid, width);
+        let random = |edit: u64, n: u64, range: usize| (unit_random(edit, n) * range as f64) as usize;
+        for edit in 0..500 {
+            // A random rectangle painted with one tile, like a brush stroke
+            let (x, y) = (random(edit, 0, width), random(edit, 1, height));
+            let (w, h) = (1 + random(edit, 2, (width - x).min(40)), 1 + random(edit, 3, (height - y).min(40)));
+            let tile = random(edit, 4, 6);
+            for row in y..y + h {
+                grid[row * width + x..row * width + x + w].fill(tile);
+            }
+            rle_update(&mut tiles, &mut amounts, &grid, width, y..y + h).unwrap();
+            let (full_tiles, full_amounts) = rle_encode(&grid, width);
+            assert!(tiles == full_tiles && amounts == full_amounts, "edit {} differs from a full encode", edit);
+        }
+    }
+
+    #[test]
+    fn update_rejects_a_grid_of_partial_rows() {
+        let grid = vec![0; 12];
+        let (mut tiles, mut amounts) = rle_encode(&grid, 4);
+        assert!(rle_update(&mut tiles, &mut amounts, &grid[..11], 4, 0..1).is_err());
+        assert!(rle_update(&mut tiles, &mut amounts, &grid, 0, 0..1).is_err());
+        assert!(rle_update(&mut tiles, &mut amounts, &grid, 4, 2..4).is_err());
+        assert!(rle_update(&mut tiles, &mut amounts, &grid, 4, 0..3).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use image::{imageops, Rgb, RgbImage};
+
+// 5x7 bitmap glyphs used for labels. Each row keeps the low 5 bits, the highest of them is the left column.
+// Lowercase letters are drawn as uppercase and anything missing as '?'
+const GLYPHS: [(char, [u8; 7]); 41] = [
+    ('A', [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001]),
+    ('B', [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110]),
+    ('C', [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110]),
+    ('D', [0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110]),
+    ('E', [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111]),
+    ('F', [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000]),
+    ('G', [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111]),
+    ('H', [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001]),
+    ('I', [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('J', [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100]),
+    ('K', [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001]),
+    ('L', [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111]),
+    ('M', [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001]),
+    ('N', [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001]),
+    ('O', [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110]),
+    ('P', [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000]),
+    ('Q', [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101]),
+    ('R', [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001]),
+    ('S', [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110]),
+    ('T', [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100]),
+    ('U', [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110]),
+    ('V', [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100]),
+    ('W', [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010]),
+    ('X', [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001]),
+    ('Y', [0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100, 0b00100]),
+    ('Z', [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111]),
+    ('0', [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110]),
+    ('1', [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    ('2', [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111]),
+    ('3', [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110]),
+    ('4', [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010]),
+    ('5', [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110]),
+    ('6', [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110]),
+    ('7', [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000]),
+    ('8', [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110]),
+    ('9', [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100]),
+    ('.', [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100]),
+    ('-', [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000]),
+    ('_', [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111]),
+    (':', [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000]),
+    ('?', [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100]),
+];
+
+// Space between thumbnails and around labels, in pixels
+const PADDING: u32 = 8;
+
+// Downscales an image so its longest side is at most `size`, keeping the aspect ratio.
+// Nearest-neighbor keeps the tile colors crisp
+pub fn thumbnail(img: &RgbImage, size: u32) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let scale = (size as f64 / width.max(height) as f64).min(1.0);
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    imageops::resize(img, new_width, new_height, imageops::FilterType::Nearest)
+}
+
+// Downscales a map preview so its longest side is at most `size`, each pixel taking the most common color
+// of the tiles it covers (the smallest color on ties). Unlike a resampled thumbnail every pixel stays
+// one of the tile colors, and thin features (rivers, roads) only survive where they dominate their cell
+pub fn minimap(img: &RgbImage, size: u32) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let scale = (size as f64 / width.max(height) as f64).min(1.0);
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    let span = |i: u32, new: u32, old: u32| (i as u64 * old as u64 / new as u64) as u32..((i + 1) as u64 * old as u64 / new as u64) as u32;
+    RgbImage::from_fn(new_width, new_height, |x, y| {
+        let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+        for sy in span(y, new_height, height) {
+            for sx in span(x, new_width, width) {
+                *counts.entry(img.get_pixel(sx, sy).0).or_default() += 1;
+            }
+        }
+        let (color, _) = counts.into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .expect("every minimap pixel covers at least one tile");
+        Rgb(color)
+    })
+}
+
+// Draws text with the built-in 5x7 font with its top-left corner at (x, y), clipped to the image
+pub fn draw_label(img: &mut RgbImage, text: &str, x: u32, y: u32, color: Rgb<u8>) {
+    for (n, ch) in text.chars().enumerate() {
+        let ch = ch.to_ascii_uppercase();
+        let rows = GLYPHS.iter()
+            .find(|(c, _)| *c == ch)
+            .or_else(|| GLYPHS.iter().find(|(c, _)| *c == '?'))
+            .map(|(_, rows)| rows)
+            .unwrap();
+        if ch == ' ' {
+            continue;
+        }
+        let left = x + n as u32 * 6;
+        for (dy, row) in rows.iter().enumerate() {
+            for dx in 0..5 {
+                let (px, py) = (left + dx, y + dy as u32);
+                if row & (0b10000 >> dx) != 0 && px < img.width() && py < img.height() {
+                    img.put_pixel(px, py, color);
+                }
+            }
+        }
+    }
+}
+
+// Arranges labelled thumbnails into a grid with the given number of columns
+pub fn contact_sheet(entries: &[(String, RgbImage)], columns: u32) -> RgbImage {
+    let columns = columns.max(1).min(entries.len().max(1) as u32);
+    let rows = (entries.len() as u32).div_ceil(columns).max(1);
+    let cell_width = entries.iter().map(|(_, t)| t.width()).max().unwrap_or(0);
+    let cell_height = entries.iter().map(|(_, t)| t.height()).max().unwrap_or(0) + 7 + PADDING;
+    let mut sheet = RgbImage::from_pixel(
+        columns * (cell_width + PADDING) + PADDING,
+        rows * (cell_height + PADDING) + PADDING,
+        Rgb([32, 32, 32]),
+    );
+    for (i, (label, thumb)) in entries.iter().enumerate() {
+        let x = PADDING + (i as u32 % columns) * (cell_width + PADDING);
+        let y = PADDING + (i as u32 / columns) * (cell_height + PADDING);
+        imageops::replace(&mut sheet, thumb, x as i64, y as i64);
+        // Labels longer than the cell are cut off
+        let fits = ((cell_width + 1) / 6) as usize;
+        let label: String = label.chars().take(fits).collect();
+        draw_label(&mut sheet, &label, x, y + thumb.height() + PADDING / 2, Rgb([230, 230, 230]));
+    }
+    sheet
+}
@@ -0,0 +1,55 @@
+// Simplification of a tile grid, for maps whose RLE would hold more runs than a loader accepts
+use std::collections::{HashMap, VecDeque};
+
+// How many RLE runs a row-major grid of this width encodes to: one per row plus one per change of tile
+pub fn count_runs(grid: &[usize], width: usize) -> usize {
+    grid.chunks(width).map(|row| 1 + row.windows(2).filter(|pair| pair[0] != pair[1]).count()).sum()
+}
+
+// Replaces every 4-connected region of one tile smaller than `min_size` tiles with the tile most common
+// along its border (the lowest index on a tie), all regions judged on the grid as it was before the
+// call. Returns how many tiles changed
+pub fn remove_speckles(grid: &mut [usize], width: usize, height: usize, min_size: usize) -> usize {
+    let neighbours = |i: usize| {
+        let (x, y) = (i % width, i / width);
+        [
+            (x > 0).then(|| i - 1),
+            (x + 1 < width).then(|| i + 1),
+            (y > 0).then(|| i - width),
+            (y + 1 < height).then(|| i + width),
+        ].into_iter().flatten()
+    };
+    let original = grid.to_vec();
+    let mut visited = vec![false; original.len()];
+    let mut changed = 0;
+    for start in 0..original.len() {
+        if visited[start] {
+            continue;
+        }
+        // Flood fill the region, counting the tiles just outside it
+        let tile = original[start];
+        let (mut region, mut border) = (vec![start], HashMap::new());
+        let mut queue = VecDeque::from([start]);
+        visited[start] = true;
+        while let Some(i) = queue.pop_front() {
+            for n in neighbours(i) {
+                if original[n] != tile {
+                    *border.entry(original[n]).or_insert(0usize) += 1;
+                } else if !visited[n] {
+                    visited[n] = true;
+                    region.push(n);
+                    queue.push_back(n);
+                }
+            }
+        }
+        if region.len() >= min_size {
+            continue;
+        }
+        let Some((&replacement, _)) = border.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))) else { continue };
+        for &i in &region {
+            grid[i] = replacement;
+        }
+        changed += region.len();
+    }
+    changed
+}
@@ -0,0 +1,53 @@
+// Line-based text files (palettes, world laws) are often edited on Windows,
+// so a UTF-8 byte order mark and CRLF endings must not leak into ids or values
+
+// Iterates the trimmed lines of a text file's content, without a leading BOM or trailing '\r'
+pub fn clean_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .strip_prefix('\u{feff}')
+        .unwrap_or(content)
+        .lines()
+        .map(|line| line.trim_end_matches('\r').trim())
+}
+
+// Iterates the records of CSV text (ex: a spreadsheet export), one at a time, as the line each starts on
+// and its trimmed fields. Fields may be quoted to hold commas, line breaks or "" for a quote, and
+// blank lines are skipped. Like `clean_lines` a leading BOM and CRLF endings are ignored
+pub fn csv_records(content: &str) -> impl Iterator<Item = (usize, Vec<String>)> + '_ {
+    let mut chars = content.strip_prefix('\u{feff}').unwrap_or(content).chars().peekable();
+    let mut line = 1;
+    std::iter::from_fn(move || loop {
+        chars.peek()?;
+        let start = line;
+        let (mut fields, mut field, mut quoted) = (Vec::new(), String::new(), false);
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' if quoted => quoted = false,
+                '"' if field.trim().is_empty() => {
+                    field.clear();
+                    quoted = true;
+                }
+                ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+                '\n' if !quoted => {
+                    line += 1;
+                    break;
+                }
+                c => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+        }
+        fields.push(field);
+        if fields.len() > 1 || !fields[0].trim().is_empty() {
+            return Some((start, fields.into_iter().map(|f| f.trim().to_string()).collect()));
+        }
+    })
+}
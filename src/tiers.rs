@@ -0,0 +1,128 @@
+// Palettes of ordered tiers, where a pixel's brightness picks its tile instead of its nearest color,
+// so heightmap-like sources become clean contour-like bands
+use std::{fs, str::FromStr};
+use image::{Pixel, RgbImage};
+use rayon::prelude::*;
+use crate::{error::{HerzError, Result}, palette::{parse_hex, Palette}, text::clean_lines};
+
+// The tiers in file order, each taking the pixels up to its brightness that no earlier tier took
+pub struct TieredPalette {
+    pub palette: Palette,
+    // Highest 0-255 brightness of each tier, increasing
+    pub thresholds: Vec<u8>,
+}
+
+impl TieredPalette {
+    // Reads a tiered palette file, one "id #RRGGBB maxBrightness" tier per line
+    pub fn load(path: &str) -> Result<TieredPalette> {
+        let content = fs::read_to_string(path).map_err(HerzError::io("read tiered palette", path))?;
+        content.parse().map_err(|e| match e {
+            HerzError::Palette(reason) => HerzError::Palette(format!("{}: {}", path, reason)),
+            e => e,
+        })
+    }
+
+    // Makes the first tier (the water) end at `level`, moving the land tiers so they keep their share
+    // of the brightness above it. The last tier still takes everything brighter
+    pub fn set_sea_level(&mut self, level: u8) -> Result<()> {
+        let land = self.thresholds.len().saturating_sub(1);
+        if land == 0 {
+            return Err(HerzError::Palette("a sea level needs a water tier and at least one land tier".to_string()));
+        }
+        if level as usize + land > 255 {
+            return Err(HerzError::Palette(format!("sea level {} leaves no room above it for {} land tiers", level, land)));
+        }
+        let (old, span) = (self.thresholds[0] as f64, 255.0 - self.thresholds[0] as f64);
+        let mut previous = level;
+        self.thresholds[0] = level;
+        for (i, threshold) in self.thresholds.iter_mut().enumerate().skip(1) {
+            let share = if span > 0.0 { (*threshold as f64 - old) / span } else { 1.0 };
+            // Rounding may bring two tiers together, each keeps at least one level, and enough stay for the rest
+            let moved = (level as f64 + share * (255.0 - level as f64)).round() as usize;
+            let moved = moved.clamp(previous as usize + 1, 255 - (land - i));
+            *threshold = moved as u8;
+            previous = *threshold;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TieredPalette {
+    type Err = HerzError;
+
+    fn from_str(content: &str) -> Result<TieredPalette> {
+        let mut entries = Vec::new();
+        let mut thresholds: Vec<u8> = Vec::new();
+        for (number, line) in clean_lines(content).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| HerzError::Palette(format!("line {}: {}", number + 1, reason));
+            let [id, hex, max] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(invalid("expected \"id #RRGGBB maxBrightness\"".to_string()));
+            };
+            let (r, g, b) = parse_hex(hex).map_err(invalid)?;
+            let max: u8 = max.parse().map_err(|_| invalid(format!("brightness {} must be a number from 0 to 255", max)))?;
+            // A tier at or below the previous one could never take a pixel
+            if let Some(&previous) = thresholds.last() && max <= previous {
+                return Err(invalid(format!("tier {} ends at {}, which is not above the previous tier's {}", id, max, previous)));
+            }
+            entries.push((id.to_string(), [r, g, b]));
+            thresholds.push(max);
+        }
+        Ok(TieredPalette { palette: Palette::from_entries(entries)?, thresholds })
+    }
+}
+
+// Number of pixels of every luminance, the brightness `match_tiers` compares to the thresholds
+pub fn luma_histogram(img: &RgbImage) -> [u64; 256] {
+    let mut histogram = [0; 256];
+    for p in img.pixels() {
+        histogram[p.to_luma()[0] as usize] += 1;
+    }
+    histogram
+}
+
+// Sea level of a heightmap-like histogram by Otsu's method: the level (water at or below it) that best
+// separates the two sides, which lands in the valley between the sea and land peaks
+pub fn otsu_level(histogram: &[u64; 256]) -> u8 {
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram.iter().enumerate().map(|(level, &n)| level as f64 * n as f64).sum();
+    let (mut below, mut below_sum) = (0u64, 0.0);
+    let (mut best, mut best_level) = (-1.0, 0);
+    for (level, &n) in histogram.iter().enumerate() {
+        below += n;
+        below_sum += level as f64 * n as f64;
+        let above = total - below;
+        if below == 0 || above == 0 {
+            continue;
+        }
+        let (mean_below, mean_above) = (below_sum / below as f64, (sum - below_sum) / above as f64);
+        let between = below as f64 * above as f64 * (mean_below - mean_above).powi(2);
+        if between > best {
+            (best, best_level) = (between, level);
+        }
+    }
+    best_level as u8
+}
+
+// Sea level leaving `percent` of the pixels at or below it, ex: 70 for a map that's mostly ocean
+pub fn percentile_level(histogram: &[u64; 256], percent: f64) -> u8 {
+    let total: u64 = histogram.iter().sum();
+    let wanted = (total as f64 * percent / 100.0).ceil() as u64;
+    let mut below = 0;
+    histogram.iter().position(|&n| {
+        below += n;
+        below >= wanted
+    }).unwrap_or(255) as u8
+}
+
+// Matches every pixel (row-major) to the first tier its luminance doesn't exceed, pixels brighter
+// than the last tier's threshold taking the last tier
+pub fn match_tiers(img: &RgbImage, thresholds: &[u8]) -> Vec<usize> {
+    let last = thresholds.len().saturating_sub(1);
+    img.as_raw().par_chunks(3).map(|p| {
+        let luma = image::Rgb([p[0], p[1], p[2]]).to_luma()[0];
+        thresholds.iter().position(|&t| luma <= t).unwrap_or(last)
+    }).collect()
+}
@@ -0,0 +1,87 @@
+// Export of a tile grid as a map for the Tiled editor (https://www.mapeditor.org), in its JSON map format
+// with one embedded tileset of plain color swatches
+use image::{Rgb, RgbImage};
+use serde_json::{json, Value};
+
+// Width and height in pixels of every swatch of the tileset image
+pub const TILE_SIZE: u32 = 8;
+// Swatches per row of the tileset image
+const COLUMNS: usize = 16;
+
+// One tile of the tileset, in tileMap order
+pub struct TiledTile {
+    pub id: String,
+    pub color: [u8; 3],
+    // Whether `color` is the id's palette color. Ids the palette doesn't have (ex: placed by a layer) get
+    // the preview color of their first tile, which matching wouldn't turn back into the id
+    pub from_palette: bool,
+}
+
+// Columns and rows of swatches in the tileset image of `count` tiles
+fn tileset_grid(count: usize) -> (u32, u32) {
+    (count.clamp(1, COLUMNS) as u32, count.div_ceil(COLUMNS).max(1) as u32)
+}
+
+// Draws every tile as a TILE_SIZE square, COLUMNS to a row in tile order
+pub fn tileset_image(tiles: &[TiledTile]) -> RgbImage {
+    let (columns, rows) = tileset_grid(tiles.len());
+    RgbImage::from_fn(columns * TILE_SIZE, rows * TILE_SIZE, |x, y| {
+        let index = ((y / TILE_SIZE) * columns + x / TILE_SIZE) as usize;
+        tiles.get(index).map_or(Rgb([0, 0, 0]), |tile| Rgb(tile.color))
+    })
+}
+
+// Builds the Tiled map of a `width` x `height` grid of tile indices (row-major from the top-left), each
+// shown as tile `index` of the tileset, so its global id is index + 1 (0 is Tiled's empty tile). Every
+// tile carries its id, and its palette color when it has one, as custom properties
+pub fn tiled_map(cells: &[usize], width: u32, height: u32, tiles: &[TiledTile], tileset_image: &str) -> Value {
+    let (columns, rows) = tileset_grid(tiles.len());
+    let properties: Vec<Value> = tiles.iter().enumerate().map(|(i, tile)| {
+        let mut properties = vec![json!({ "name": "id", "type": "string", "value": tile.id })];
+        if tile.from_palette {
+            let [r, g, b] = tile.color;
+            properties.push(json!({ "name": "color", "type": "color", "value": format!("#ff{:02x}{:02x}{:02x}", r, g, b) }));
+        }
+        json!({ "id": i, "properties": properties })
+    }).collect();
+    let data: Vec<usize> = cells.iter().map(|&index| index + 1).collect();
+    json!({
+        "type": "map",
+        "version": "1.10",
+        "orientation": "orthogonal",
+        "renderorder": "right-down",
+        "infinite": false,
+        "width": width,
+        "height": height,
+        "tilewidth": TILE_SIZE,
+        "tileheight": TILE_SIZE,
+        "nextlayerid": 2,
+        "nextobjectid": 1,
+        "layers": [{
+            "id": 1,
+            "name": "tiles",
+            "type": "tilelayer",
+            "x": 0,
+            "y": 0,
+            "width": width,
+            "height": height,
+            "opacity": 1,
+            "visible": true,
+            "data": data,
+        }],
+        "tilesets": [{
+            "firstgid": 1,
+            "name": "herzmapper",
+            "image": tileset_image,
+            "imagewidth": columns * TILE_SIZE,
+            "imageheight": rows * TILE_SIZE,
+            "tilewidth": TILE_SIZE,
+            "tileheight": TILE_SIZE,
+            "tilecount": tiles.len(),
+            "columns": columns,
+            "margin": 0,
+            "spacing": 0,
+            "tiles": properties,
+        }],
+    })
+}
@@ -0,0 +1,418 @@
+use std::{borrow::Cow, collections::HashMap, fs::File, io::{self, BufReader, BufWriter, Read, Write}};
+#[cfg(feature = "zstd")]
+use std::io::BufRead;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use serde::{Serialize, Serializer, ser::{SerializeMap, SerializeSeq}};
+use serde_json::Value;
+use crate::{atomic::write_atomic, budget::{into_herz, Budget, BudgetWriter}, error::{HerzError, Result}};
+
+// Tiles along each side of one map cell; the map's width and height are counted in cells
+pub const CELL_SIZE: u64 = 64;
+
+// Compression of the written map. Only zlib is the .wbox format the game can load
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Container {
+    #[default]
+    Zlib,
+    // A zstd stream, smaller and faster to write for archiving, that the game can't read
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+// Effort spent compressing the map: fast is the default, best gives the smallest files of the container
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CompressionLevel {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl CompressionLevel {
+    fn zlib(self) -> Compression {
+        match self {
+            CompressionLevel::Fast => Compression::fast(),
+            CompressionLevel::Default => Compression::default(),
+            CompressionLevel::Best => Compression::best(),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    fn zstd(self) -> i32 {
+        match self {
+            CompressionLevel::Fast => 1,
+            CompressionLevel::Default => 3,
+            CompressionLevel::Best => 19,
+        }
+    }
+}
+
+// First bytes of every zstd frame
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+
+// The map JSON with its large RLE arrays kept outside of the serde_json tree.
+// Each entry in `layers` replaces the top-level key of the same name while serializing,
+// so the output is identical to inserting the arrays into map_data without building them as Values
+pub struct WboxDocument<'a> {
+    pub map_data: &'a Value,
+    pub layers: HashMap<&'a str, Layer<'a>>,
+    // JSON without indentation or line breaks instead of indented. The game reads both, compact is
+    // smaller to compress
+    pub compact: bool,
+}
+
+// The data streamed in place of a top-level key
+pub enum Layer<'a> {
+    // One array per row, like tileArray
+    Rows(&'a [Vec<usize>]),
+    // tileArray and tileAmounts split into bands for streaming loaders, written as a list of
+    // {"row": index of the band's first row, "tileArray": [...], "tileAmounts": [...]}
+    Bands { rows: usize, tile_array: &'a [Vec<usize>], tile_amounts: &'a [Vec<usize>] },
+}
+
+impl Serialize for Layer<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match *self {
+            Layer::Rows(rows) => rows.serialize(serializer),
+            Layer::Bands { rows, tile_array, tile_amounts } => {
+                let rows = rows.max(1);
+                let mut bands = serializer.serialize_seq(Some(tile_array.len().div_ceil(rows)))?;
+                for (i, (tiles, amounts)) in tile_array.chunks(rows).zip(tile_amounts.chunks(rows)).enumerate() {
+                    bands.serialize_element(&Band { row: i * rows, tile_array: tiles, tile_amounts: amounts })?;
+                }
+                bands.end()
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Band<'a> {
+    row: usize,
+    #[serde(rename = "tileArray")]
+    tile_array: &'a [Vec<usize>],
+    #[serde(rename = "tileAmounts")]
+    tile_amounts: &'a [Vec<usize>],
+}
+
+impl Serialize for WboxDocument<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let Some(object) = self.map_data.as_object() else {
+            return self.map_data.serialize(serializer);
+        };
+        let mut map = serializer.serialize_map(Some(object.len()))?;
+        for (key, value) in object {
+            match self.layers.get(key.as_str()) {
+                Some(layer) => map.serialize_entry(key, layer)?,
+                None => map.serialize_entry(key, value)?,
+            }
+        }
+        map.end()
+    }
+}
+
+// Streams the map JSON straight into a zlib-compressed .wbox file, never holding the full JSON text in memory
+pub fn compress_to_wbox(document: &WboxDocument, output_path: &str) -> Result<()> {
+    compress_to_wbox_within(document, output_path, &Budget::unlimited(), Container::Zlib, CompressionLevel::Fast)
+}
+
+// Like compress_to_wbox in any container, but stops with HerzError::TimeLimit or OutputLimit as soon as
+// the budget runs out, leaving no partial file behind
+pub fn compress_to_wbox_within(
+    document: &WboxDocument, output_path: &str, budget: &Budget, container: Container, level: CompressionLevel,
+) -> Result<()> {
+    write_atomic(output_path, |temp| {
+        File::create(temp)
+            .and_then(|file| compress_wbox_in(document, BudgetWriter::new(file, budget), container, level))
+            .map_err(|e| into_herz(e, HerzError::io("write", output_path)))
+    })
+}
+
+// Compresses the map JSON into memory within a budget, for outputs that don't go to a file
+pub fn compress_wbox_to_vec(document: &WboxDocument, budget: &Budget, container: Container, level: CompressionLevel) -> Result<Vec<u8>> {
+    Ok(compress_counted(document, budget, container, level)?.0)
+}
+
+// Compresses the map JSON into memory like compress_wbox_to_vec, also returning the size of the
+// JSON before compression, so the ratio of a level can be measured without writing anything
+pub fn compress_counted(document: &WboxDocument, budget: &Budget, container: Container, level: CompressionLevel) -> Result<(Vec<u8>, u64)> {
+    let mut bytes = Vec::new();
+    let json_bytes = compress_json(document, BudgetWriter::new(&mut bytes, budget), container, level)
+        .map_err(|e| into_herz(e, HerzError::io("compress", "the map in memory")))?;
+    Ok((bytes, json_bytes))
+}
+
+// Compresses the map JSON into any writer with the given container and level
+pub fn compress_wbox_in<W: Write>(document: &WboxDocument, writer: W, container: Container, level: CompressionLevel) -> io::Result<()> {
+    compress_json(document, writer, container, level).map(drop)
+}
+
+// Compresses the map JSON into any writer, ex: a Vec<u8> to keep the .wbox bytes in memory
+pub fn compress_wbox<W: Write>(document: &WboxDocument, writer: W) -> io::Result<()> {
+    compress_wbox_in(document, writer, Container::Zlib, CompressionLevel::Fast)
+}
+
+// Streams the JSON into the container's encoder, returning how many JSON bytes went in
+fn compress_json<W: Write>(document: &WboxDocument, writer: W, container: Container, level: CompressionLevel) -> io::Result<u64> {
+    match container {
+        Container::Zlib => {
+            let mut json = CountingWriter { inner: BufWriter::new(ZlibEncoder::new(writer, level.zlib())), written: 0 };
+            write_json(&mut json, document)?;
+            json.inner.into_inner().map_err(|e| e.into_error())?.finish()?.flush()?;
+            Ok(json.written)
+        }
+        #[cfg(feature = "zstd")]
+        Container::Zstd => {
+            let mut json = CountingWriter { inner: BufWriter::new(zstd::Encoder::new(writer, level.zstd())?), written: 0 };
+            write_json(&mut json, document)?;
+            json.inner.into_inner().map_err(|e| e.into_error())?.finish()?.flush()?;
+            Ok(json.written)
+        }
+    }
+}
+
+fn write_json<W: Write>(writer: W, document: &WboxDocument) -> serde_json::Result<()> {
+    if document.compact { serde_json::to_writer(writer, document) } else { serde_json::to_writer_pretty(writer, document) }
+}
+
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Inflates a .wbox file back into its map JSON. With the zstd feature, zstd archives are read as well
+pub fn read_wbox(path: &str) -> Result<Value> {
+    let file = File::open(path).map_err(HerzError::io("open", path))?;
+    let reader = BufReader::new(file);
+    let mut json = String::new();
+    #[cfg(feature = "zstd")]
+    let reader = {
+        let mut reader = reader;
+        if reader.fill_buf().map_err(HerzError::io("read", path))?.starts_with(ZSTD_MAGIC) {
+            zstd::Decoder::with_buffer(reader)
+                .and_then(|mut decoder| decoder.read_to_string(&mut json))
+                .map_err(HerzError::decode(".wbox", path))?;
+            return serde_json::from_str(&json).map_err(HerzError::decode(".wbox", path));
+        }
+        reader
+    };
+    ZlibDecoder::new(reader)
+        .read_to_string(&mut json)
+        .map_err(HerzError::decode(".wbox", path))?;
+    serde_json::from_str(&json).map_err(HerzError::decode(".wbox", path))
+}
+
+// The map with the bands of a --chunked-tiles map (see `Layer::Bands`) joined back into top-level tileArray
+// and tileAmounts rows in place of "chunks", so it reads like any other map. Maps without chunks are
+// returned as they are. Fails when the bands don't follow each other or a band's lists don't match
+pub fn join_chunks(map_data: &Value) -> Result<Cow<'_, Value>> {
+    let Some(chunks) = map_data.get("chunks") else {
+        return Ok(Cow::Borrowed(map_data));
+    };
+    let broken = |reason: String| HerzError::Template(format!("chunked map {}", reason));
+    if map_data.get("tileArray").is_some() || map_data.get("tileAmounts").is_some() {
+        return Err(broken("has both chunks and a top-level tileArray or tileAmounts".to_string()));
+    }
+    let bands = chunks.as_array().ok_or_else(|| broken("has chunks that aren't a list of bands".to_string()))?;
+    let (mut tile_array, mut tile_amounts) = (Vec::new(), Vec::new());
+    for (i, band) in bands.iter().enumerate() {
+        let row = band.get("row").unwrap_or(&Value::Null);
+        if row.as_u64() != Some(tile_array.len() as u64) {
+            return Err(broken(format!("band {} starts at row {} instead of {}", i, row, tile_array.len())));
+        }
+        match (band.get("tileArray").and_then(Value::as_array), band.get("tileAmounts").and_then(Value::as_array)) {
+            (Some(tiles), Some(amounts)) if tiles.len() == amounts.len() => {
+                tile_array.extend(tiles.iter().cloned());
+                tile_amounts.extend(amounts.iter().cloned());
+            }
+            (Some(tiles), Some(amounts)) => {
+                return Err(broken(format!("band {} has {} tileArray rows but {} tileAmounts rows", i, tiles.len(), amounts.len())));
+            }
+            _ => return Err(broken(format!("band {} has no tileArray and tileAmounts arrays", i))),
+        }
+    }
+    let mut joined = serde_json::Map::new();
+    for (key, value) in map_data.as_object().expect("only objects have keys") {
+        if key == "chunks" {
+            joined.insert("tileArray".to_string(), Value::Array(std::mem::take(&mut tile_array)));
+            joined.insert("tileAmounts".to_string(), Value::Array(std::mem::take(&mut tile_amounts)));
+        } else {
+            joined.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(Cow::Owned(Value::Object(joined)))
+}
+
+// Collects per-row problems, listing the first few and counting the rest
+struct Violations {
+    list: Vec<String>,
+}
+
+impl Violations {
+    fn report(&mut self, kind: &str, items: Vec<String>) {
+        const SHOWN: usize = 10;
+        for item in items.iter().take(SHOWN) {
+            self.list.push(format!("{}: {}", kind, item));
+        }
+        if items.len() > SHOWN {
+            self.list.push(format!("{}: ... and {} more", kind, items.len() - SHOWN));
+        }
+    }
+}
+
+// Checks a map against the format the game expects and returns every violation found (empty when valid).
+// A --chunked-tiles map is checked as the rows its bands join into
+pub fn validate(map_data: &Value) -> Vec<String> {
+    if map_data.get("chunks").is_some() {
+        return match join_chunks(map_data) {
+            Ok(joined) => validate(&joined),
+            Err(e) => vec![e.to_string()],
+        };
+    }
+    let mut violations = Violations { list: Vec::new() };
+    let Some(object) = map_data.as_object() else {
+        return vec!["Map JSON is not an object".to_string()];
+    };
+    for key in ["width", "height", "tileMap", "tileArray", "tileAmounts"] {
+        if !object.contains_key(key) {
+            violations.list.push(format!("Missing required field {}", key));
+        }
+    }
+
+    let dimension = |key: &str| map_data[key].as_u64().filter(|&v| v > 0);
+    let (width, height) = (dimension("width"), dimension("height"));
+    for (key, value) in [("width", width), ("height", height)] {
+        if object.contains_key(key) && value.is_none() {
+            violations.list.push(format!("{} must be a positive integer", key));
+        }
+    }
+    let tile_map_len = match map_data["tileMap"].as_array() {
+        Some(tile_map) => {
+            let bad: Vec<String> = tile_map.iter().enumerate()
+                .filter(|(_, v)| !v.is_string())
+                .map(|(i, v)| format!("entry {} is {}", i, v))
+                .collect();
+            violations.report("tileMap entry is not a string", bad);
+            Some(tile_map.len())
+        }
+        None if object.contains_key("tileMap") => {
+            violations.list.push("tileMap must be an array".to_string());
+            None
+        }
+        None => None,
+    };
+
+    let rows = |key: &str| -> Option<Vec<Vec<u64>>> { serde_json::from_value(map_data[key].clone()).ok() };
+    let (tiles, amounts) = (rows("tileArray"), rows("tileAmounts"));
+    for (key, value) in [("tileArray", &tiles), ("tileAmounts", &amounts)] {
+        if object.contains_key(key) && value.is_none() {
+            violations.list.push(format!("{} must be an array of rows of non-negative integers", key));
+        }
+    }
+
+    if let Some(tiles) = &tiles {
+        if let Some(len) = tile_map_len {
+            let bad: Vec<String> = tiles.iter().enumerate()
+                .flat_map(|(y, row)| row.iter().filter(|&&t| t as usize >= len).map(move |t| format!("row {} uses index {}", y, t)))
+                .collect();
+            violations.report(&format!("tileArray index outside tileMap (length {})", len), bad);
+        }
+        if let Some(amounts) = &amounts {
+            if tiles.len() != amounts.len() {
+                violations.list.push(format!("tileArray has {} rows but tileAmounts has {}", tiles.len(), amounts.len()));
+            }
+            let bad: Vec<String> = tiles.iter().zip(amounts).enumerate()
+                .filter(|(_, (t, a))| t.len() != a.len())
+                .map(|(y, (t, a))| format!("row {} has {} tiles and {} amounts", y, t.len(), a.len()))
+                .collect();
+            violations.report("tileArray and tileAmounts rows differ", bad);
+        }
+    }
+
+    if let (Some(width), Some(height)) = (width, height) {
+        let (row_len, row_count) = (width * CELL_SIZE, height * CELL_SIZE);
+        let total_tiles = row_len * row_count;
+        if let Some(amounts) = &amounts {
+            if amounts.len() as u64 != row_count {
+                violations.list.push(format!("tileAmounts has {} rows, expected height*{} = {}", amounts.len(), CELL_SIZE, row_count));
+            }
+            let bad: Vec<String> = amounts.iter().enumerate()
+                .map(|(y, row)| (y, row.iter().sum::<u64>()))
+                .filter(|&(_, sum)| sum != row_len)
+                .map(|(y, sum)| format!("row {} covers {} tiles", y, sum))
+                .collect();
+            violations.report(&format!("tileAmounts row does not cover width*{} = {} tiles", CELL_SIZE, row_len), bad);
+            let sum: u64 = amounts.iter().flatten().sum();
+            if sum != total_tiles {
+                violations.list.push(format!("sum(tileAmounts) is {}, expected width*height*{} = {}", sum, CELL_SIZE * CELL_SIZE, total_tiles));
+            }
+        }
+        match map_data.get("frozen_tiles").map(|f| serde_json::from_value::<Vec<u64>>(f.clone())) {
+            Some(Ok(frozen)) => {
+                let bad: Vec<String> = frozen.iter().filter(|&&i| i >= total_tiles).map(|i| i.to_string()).collect();
+                violations.report(&format!("frozen_tiles index outside the map ({} tiles)", total_tiles), bad);
+            }
+            Some(Err(_)) => violations.list.push("frozen_tiles must be an array of non-negative integers".to_string()),
+            None => {}
+        }
+    }
+    violations.list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // The JSON a run writes for `map_data`, with the rows either as plain lists or in bands of `band_rows`
+    fn written(map_data: &Value, tile_array: &[Vec<usize>], tile_amounts: &[Vec<usize>], band_rows: Option<usize>) -> Value {
+        let layers = match band_rows {
+            Some(rows) => HashMap::from([("chunks", Layer::Bands { rows, tile_array, tile_amounts })]),
+            None => HashMap::from([("tileArray", Layer::Rows(tile_array)), ("tileAmounts", Layer::Rows(tile_amounts))]),
+        };
+        serde_json::to_value(WboxDocument { map_data, layers, compact: true }).unwrap()
+    }
+
+    #[test]
+    fn chunked_map_validates_like_the_unchunked_one() {
+        // 2x2 cells: 128 rows of one run each, in bands of 50, 50 and 28 rows
+        let tile_array: Vec<Vec<usize>> = (0..128).map(|y| vec![y % 2]).collect();
+        let tile_amounts = vec![vec![128]; 128];
+        let plain = written(&json!({ "width": 2, "height": 2, "tileMap": ["a", "b"], "tileArray": [], "tileAmounts": [] }), &tile_array, &tile_amounts, None);
+        let chunked = written(&json!({ "width": 2, "height": 2, "tileMap": ["a", "b"], "chunks": [] }), &tile_array, &tile_amounts, Some(50));
+        assert_eq!(chunked["chunks"].as_array().unwrap().len(), 3);
+        assert_eq!(validate(&plain), Vec::<String>::new());
+        assert_eq!(validate(&chunked), Vec::<String>::new());
+        let joined = join_chunks(&chunked).unwrap();
+        assert_eq!(joined["tileArray"], plain["tileArray"]);
+        assert_eq!(joined["tileAmounts"], plain["tileAmounts"]);
+        assert!(joined.get("chunks").is_none());
+        // A plain map is passed through untouched
+        assert!(matches!(join_chunks(&plain).unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn broken_bands_are_reported_as_a_chunked_map() {
+        let mut chunked = written(&json!({ "width": 2, "height": 2, "tileMap": ["a"], "chunks": [] }), &vec![vec![0]; 128], &vec![vec![128]; 128], Some(64));
+        chunked["chunks"][1]["row"] = json!(60);
+        assert_eq!(validate(&chunked), ["Invalid tile data: chunked map band 1 starts at row 60 instead of 64"]);
+        chunked["chunks"][1]["row"] = json!(64);
+        chunked["chunks"][1]["tileAmounts"].as_array_mut().unwrap().pop();
+        assert_eq!(validate(&chunked), ["Invalid tile data: chunked map band 1 has 64 tileArray rows but 63 tileAmounts rows"]);
+        chunked["chunks"] = json!(5);
+        assert_eq!(validate(&chunked), ["Invalid tile data: chunked map has chunks that aren't a list of bands"]);
+    }
+}